@@ -34,6 +34,12 @@ pub fn build_dhcp_to_layer2(
     dhcp_packet: Vec<u8>,
     interface: &NetworkInterface,
 ) -> MutableEthernetPacket {
+    build_dhcp_frame(dhcp_packet, interface.mac.unwrap())
+}
+
+/// Wrap a DHCP client message in a broadcast frame sent from `source`, which does not have to
+/// be the address of the interface it goes out on.
+pub fn build_dhcp_frame(dhcp_packet: Vec<u8>, source: MacAddr) -> MutableEthernetPacket<'static> {
     let source_ipv4 = Ipv4Addr::new(0, 0, 0, 0);
     let destination_ipv4 = Ipv4Addr::new(255, 255, 255, 255);
 
@@ -83,7 +89,7 @@ pub fn build_dhcp_to_layer2(
     let mut ethernet_packet = MutableEthernetPacket::owned(ethernet_buffer).unwrap();
     {
         ethernet_packet.set_destination(MacAddr::broadcast());
-        ethernet_packet.set_source(interface.mac.unwrap());
+        ethernet_packet.set_source(source);
         ethernet_packet.set_ethertype(EtherTypes::Ipv4);
         ethernet_packet.set_payload(ipv4_packet.packet());
     }
@@ -0,0 +1,144 @@
+use local_net::RTNetlinkError;
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+
+/// Failures specific to a DHCP exchange.
+#[derive(Debug)]
+pub enum DhcpError {
+    /// No reply for our transaction arrived before the deadline.
+    Timeout,
+    /// The server answered with DHCPNAK.
+    Nak {
+        server: Option<Ipv4Addr>,
+        message: Option<String>,
+    },
+    /// A reply for our transaction could not be parsed.
+    Malformed(String),
+}
+
+/// The error type for everything outside of `local_net`.
+#[derive(Debug)]
+pub enum Error {
+    Dhcp(DhcpError),
+    /// The operation on the named interface requires privileges we don't have.
+    PermissionDenied(String),
+    InterfaceNotFound(String),
+    Netlink(RTNetlinkError),
+    Io(io::Error),
+    /// The change was accepted, but reading it back did not match.
+    ValidationFailed {
+        expected: String,
+        found: String,
+    },
+    /// A vendor database file could not be parsed.
+    InvalidDatabase(String),
+    /// Text that is not a hardware address in any format we know.
+    InvalidAddress(String),
+    /// The caller passed something we can't work with.
+    InvalidArgument(String),
+    /// No uplink with this interface index was added.
+    UnknownUplink(u32),
+}
+
+impl Error {
+    /// Classify an `io::Error` raised while operating on `interface`.
+    pub fn from_io(interface: &str, error: io::Error) -> Error {
+        match error.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) => {
+                Error::PermissionDenied(interface.to_string())
+            }
+            Some(libc::ENODEV) | Some(libc::ENXIO) => {
+                Error::InterfaceNotFound(interface.to_string())
+            }
+            _ => Error::Io(error),
+        }
+    }
+
+    /// Classify an error from `local_net` raised while operating on `interface`.
+    pub fn from_netlink(interface: &str, error: RTNetlinkError) -> Error {
+        match error {
+            RTNetlinkError::PermissionDenied => Error::PermissionDenied(interface.to_string()),
+            RTNetlinkError::InterfaceNotFound => Error::InterfaceNotFound(interface.to_string()),
+            RTNetlinkError::ValidationFailed { expected, found } => {
+                Error::ValidationFailed { expected, found }
+            }
+            RTNetlinkError::InvalidArgument(reason) => Error::InvalidArgument(reason),
+            e => Error::Netlink(e),
+        }
+    }
+}
+
+impl From<DhcpError> for Error {
+    fn from(error: DhcpError) -> Self {
+        Error::Dhcp(error)
+    }
+}
+
+impl From<RTNetlinkError> for Error {
+    fn from(error: RTNetlinkError) -> Self {
+        Error::Netlink(error)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl std::error::Error for DhcpError {}
+
+impl fmt::Display for DhcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DhcpError::Timeout => write!(f, "Timed out waiting for a DHCP reply"),
+            DhcpError::Nak { server, message } => {
+                write!(f, "DHCP server")?;
+                if let Some(server) = server {
+                    write!(f, " {}", server)?;
+                }
+                write!(f, " declined the request")?;
+                if let Some(message) = message {
+                    write!(f, ": {}", message)?;
+                }
+                Ok(())
+            }
+            DhcpError::Malformed(reason) => write!(f, "Malformed DHCP reply: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Dhcp(e) => Some(e),
+            Error::Netlink(e) => Some(e),
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Dhcp(e) => write!(f, "{}", e),
+            Error::PermissionDenied(interface) => {
+                write!(f, "Permission denied on interface {}", interface)
+            }
+            Error::InterfaceNotFound(interface) => write!(f, "Interface {} not found", interface),
+            Error::Netlink(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::ValidationFailed { expected, found } => write!(
+                f,
+                "Validation failed: expected {}, found {}",
+                expected, found
+            ),
+            Error::InvalidDatabase(reason) => write!(f, "Invalid vendor database: {}", reason),
+            Error::InvalidAddress(text) => write!(f, "Invalid hardware address: {}", text),
+            Error::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+            Error::UnknownUplink(iface_idx) => write!(f, "No uplink with index {}", iface_idx),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+// Several apparent devices on one interface. Every identity is a macvlan child of the parent
+// with its own generated MAC and its own DHCP lease. Source based routing (one table and one
+// rule per identity) makes sure traffic bound to an identity's address leaves through it.
+// Each identity renews its lease on its own, like a separate DHCP client would.
+use local_net::{
+    add_address, add_macvlan, add_route, add_source_rule, del_link, del_source_rule,
+    get_link_index, replace_address, Address, Isolation, Route, Table,
+};
+use log::{info, warn};
+use netlink_packet_route::IFA_F_NOPREFIXROUTE;
+use pnet::util::MacAddr;
+use rtnetlink::Handle;
+use std::collections::BTreeSet;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::task::JoinHandle;
+
+use crate::error::{DhcpError, Error};
+use crate::lease::Lease;
+use crate::mac::set_mac;
+use crate::mac_generator::{random_mac, DeviceClass};
+use crate::oui::OuiDatabase;
+use crate::send_dhcp::{obtain_lease, release_lease, renew_lease};
+
+/// Routing tables and rule priorities are allocated from here up, one per identity.
+pub const IDENTITY_TABLE_BASE: u32 = 1000;
+
+// Tables in use by identities of any `Identities` in this process.
+fn tables_in_use() -> &'static Mutex<BTreeSet<u32>> {
+    static TABLES: OnceLock<Mutex<BTreeSet<u32>>> = OnceLock::new();
+    TABLES.get_or_init(|| Mutex::new(BTreeSet::new()))
+}
+
+fn allocate_table() -> u32 {
+    let mut tables = tables_in_use().lock().unwrap();
+    let table = (IDENTITY_TABLE_BASE..)
+        .find(|table| !tables.contains(table))
+        .expect("fewer identities than table ids");
+    tables.insert(table);
+    table
+}
+
+fn free_table(table: u32) {
+    tables_in_use().lock().unwrap().remove(&table);
+}
+
+// Answer ARP only for addresses on the interface asked (arp_ignore 1) and announce with
+// the address of the interface sending (arp_announce 2). Without this the parent answers
+// for the identities' addresses as well, with its own MAC. Returns the old values.
+fn set_arp_sysctls(interface: &str, values: [&str; 2]) -> Result<[String; 2], Error> {
+    let mut old = [String::new(), String::new()];
+    for (i, (name, value)) in ["arp_ignore", "arp_announce"]
+        .iter()
+        .zip(values)
+        .enumerate()
+    {
+        let path = format!("/proc/sys/net/ipv4/conf/{}/{}", interface, name);
+        old[i] = fs::read_to_string(&path)
+            .map_err(|e| Error::from_io(interface, e))?
+            .trim()
+            .to_string();
+        fs::write(&path, value).map_err(|e| Error::from_io(interface, e))?;
+    }
+    Ok(old)
+}
+
+/// What an identity should look like.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdentitySpec {
+    /// Name of the macvlan link. At most 15 bytes.
+    pub name: String,
+    pub class: Option<DeviceClass>,
+}
+
+// The identity's address without the subnet route, which goes in its own table instead.
+fn lease_address(lease: &Lease, iface_idx: u32) -> Result<Address, Error> {
+    let mut address = lease.to_address(iface_idx)?;
+    address.flags |= IFA_F_NOPREFIXROUTE;
+    Ok(address)
+}
+
+// Renew `lease` whenever it is due and extend the address with it, until the lease is lost.
+async fn keep_renewed(handle: Handle, name: String, iface_idx: u32, lease: Arc<Mutex<Lease>>) {
+    loop {
+        let current = lease.lock().unwrap().clone();
+        let Some(wait) = current.next_renewal() else {
+            if current.expired() {
+                warn!("The lease for {} on {} has expired", current.address, name);
+            }
+            return;
+        };
+        tokio::time::sleep(wait).await;
+
+        let renewed = tokio::task::spawn_blocking(move || renew_lease(&current))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))
+            .and_then(|renewed| renewed);
+        let renewed = match renewed {
+            Ok(renewed) => renewed,
+            // The address has to go; the kernel drops it when its lifetime runs out.
+            Err(e @ Error::Dhcp(DhcpError::Nak { .. })) => {
+                warn!("Lost the lease on {}: {}", name, e);
+                return;
+            }
+            // Try again later, while the lease lasts.
+            Err(e) => {
+                warn!("Failed to renew the lease on {}: {}", name, e);
+                continue;
+            }
+        };
+
+        let extended = match lease_address(&renewed, iface_idx) {
+            Ok(address) => replace_address(&handle, &address)
+                .await
+                .map_err(|e| Error::from_netlink(&name, e)),
+            Err(e) => Err(e),
+        };
+        match extended {
+            Ok(()) => info!("Renewed the lease for {} on {}", renewed.address, name),
+            Err(e) => warn!("Failed to extend {} on {}: {}", renewed.address, name, e),
+        }
+        *lease.lock().unwrap() = renewed;
+    }
+}
+
+#[derive(Debug)]
+pub struct Identity {
+    pub name: String,
+    pub iface_idx: u32,
+    pub mac: MacAddr,
+    /// Routing table (and rule priority) traffic from this identity uses.
+    pub table: u32,
+    // Replaced by the renewal task every time it renews.
+    lease: Arc<Mutex<Lease>>,
+    renewal: JoinHandle<()>,
+}
+
+impl Identity {
+    pub fn address(&self) -> Ipv4Addr {
+        self.lease.lock().unwrap().address
+    }
+
+    /// The lease as last renewed.
+    pub fn lease(&self) -> Lease {
+        self.lease.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Identity {
+    fn drop(&mut self) {
+        self.renewal.abort();
+    }
+}
+
+pub struct Identities {
+    handle: Handle,
+    parent: String,
+    identities: Vec<Identity>,
+    // arp_ignore and arp_announce of the parent before we changed them.
+    parent_arp: Option<[String; 2]>,
+}
+
+impl Identities {
+    /// Create one identity per spec on `parent`. If any of them fails, the ones already
+    /// created are torn down again. Routing tables are allocated from `IDENTITY_TABLE_BASE`
+    /// up, skipping the ones other identities in this process use.
+    pub async fn create(
+        handle: Handle,
+        parent: &str,
+        specs: &[IdentitySpec],
+    ) -> Result<Identities, Error> {
+        let parent_idx = get_link_index(&handle, parent)
+            .await
+            .map_err(|e| Error::from_netlink(parent, e))?;
+
+        let mut identities = Identities {
+            handle,
+            parent: parent.to_string(),
+            identities: Vec::new(),
+            parent_arp: Some(set_arp_sysctls(parent, ["1", "2"])?),
+        };
+
+        for spec in specs {
+            let table = allocate_table();
+            match identities.add(parent_idx, spec, table).await {
+                Ok(identity) => identities.identities.push(identity),
+                Err(e) => {
+                    free_table(table);
+                    if let Err(cleanup) = identities.destroy().await {
+                        warn!("Failed to clean up identities on {}: {}", parent, cleanup);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(identities)
+    }
+
+    async fn add(
+        &self,
+        parent_idx: u32,
+        spec: &IdentitySpec,
+        table: u32,
+    ) -> Result<Identity, Error> {
+        if spec.name.is_empty() || spec.name.len() >= libc::IFNAMSIZ {
+            return Err(Error::InvalidArgument(format!(
+                "{:?} is not a valid interface name",
+                spec.name
+            )));
+        }
+        let mac = random_mac(OuiDatabase::embedded(), spec.class).ok_or_else(|| {
+            Error::InvalidDatabase(format!(
+                "No vendor in the database matches {:?}",
+                spec.class
+            ))
+        })?;
+
+        // Bridge mode so identities can still reach each other.
+        let iface_idx = add_macvlan(&self.handle, parent_idx, &spec.name, Isolation::Bridge)
+            .await
+            .map_err(|e| Error::from_netlink(&spec.name, e))?;
+
+        match self.bring_up(iface_idx, &spec.name, mac, table).await {
+            Ok(lease) => {
+                let lease = Arc::new(Mutex::new(lease));
+                let renewal = tokio::spawn(keep_renewed(
+                    self.handle.clone(),
+                    spec.name.clone(),
+                    iface_idx,
+                    lease.clone(),
+                ));
+                Ok(Identity {
+                    name: spec.name.clone(),
+                    iface_idx,
+                    mac,
+                    table,
+                    lease,
+                    renewal,
+                })
+            }
+            Err(e) => {
+                if let Err(cleanup) = del_link(&self.handle, iface_idx).await {
+                    warn!("Failed to delete macvlan {}: {}", spec.name, cleanup);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn bring_up(
+        &self,
+        iface_idx: u32,
+        name: &str,
+        mac: MacAddr,
+        table: u32,
+    ) -> Result<Lease, Error> {
+        set_mac(&self.handle, name, mac).await?;
+
+        let interface = name.to_string();
+        let lease = tokio::task::spawn_blocking(move || obtain_lease(&interface))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))??;
+
+        match self.configure(iface_idx, name, &lease, table).await {
+            Ok(()) => Ok(lease),
+            Err(e) => {
+                // The server would otherwise keep the address for us until the lease runs out.
+                if let Err(release) = release_lease(&lease) {
+                    warn!("Failed to release the lease on {}: {}", name, release);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn configure(
+        &self,
+        iface_idx: u32,
+        name: &str,
+        lease: &Lease,
+        table: u32,
+    ) -> Result<(), Error> {
+        // Like the parent, answer ARP only for our own address.
+        set_arp_sysctls(name, ["1", "2"])?;
+
+        // The subnet route goes in the identity's table below, not in main.
+        let address = lease_address(lease, iface_idx)?;
+        add_address(&self.handle, &address)
+            .await
+            .map_err(|e| Error::from_netlink(name, e))?;
+
+        // The identity's own table: its subnet and its default route.
+        let prefix_len = lease.prefix_len();
+        let subnet = Ipv4Addr::from(u32::from(lease.address) & u32::from(lease.netmask));
+        let mut routes = vec![Route::new(iface_idx, subnet.into(), prefix_len, None)];
+        if let Some(gateway) = lease.gateway() {
+            routes.push(Route::new(
+                iface_idx,
+                Ipv4Addr::UNSPECIFIED.into(),
+                0,
+                Some(gateway.into()),
+            ));
+        }
+
+        let configured = async {
+            for mut route in routes {
+                route.table = Table::from(table);
+                add_route(&self.handle, route).await?;
+            }
+            add_source_rule(&self.handle, lease.address, 32, table, table).await
+        };
+        configured.await.map_err(|e| Error::from_netlink(name, e))
+    }
+
+    pub fn parent(&self) -> &str {
+        &self.parent
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Identity> {
+        self.identities.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Identity> {
+        self.identities
+            .iter()
+            .find(|identity| identity.name == name)
+    }
+
+    /// Release every lease and remove the rules and links. Keeps going after a failure and
+    /// returns the first error.
+    pub async fn destroy(&mut self) -> Result<(), Error> {
+        let mut first_error = None;
+
+        while let Some(identity) = self.identities.pop() {
+            identity.renewal.abort();
+            // The server expires it eventually, so this one doesn't fail the teardown.
+            if let Err(e) = release_lease(&identity.lease()) {
+                warn!("Failed to release the lease on {}: {}", identity.name, e);
+            }
+
+            let res = del_source_rule(&self.handle, identity.address(), 32, identity.table)
+                .await
+                .map_err(|e| Error::from_netlink(&identity.name, e));
+            // Deleting the link takes its addresses and routes with it.
+            let res = res.and(
+                del_link(&self.handle, identity.iface_idx)
+                    .await
+                    .map_err(|e| Error::from_netlink(&identity.name, e)),
+            );
+            if let Err(e) = res {
+                first_error.get_or_insert(e);
+            }
+            free_table(identity.table);
+        }
+
+        if let Some(old) = self.parent_arp.take() {
+            if let Err(e) = set_arp_sysctls(&self.parent, [&old[0], &old[1]]) {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use dhcproto::v4;
+use local_net::{del_address, get_addresses, replace_address, set_default_route, Address};
+use pnet::util::MacAddr;
+use rtnetlink::Handle;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+use crate::error::{DhcpError, Error};
+
+/// An address bound to us by a DHCP server (from a DHCPACK).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub server_id: Ipv4Addr,
+    pub lease_time: Option<Duration>,
+    /// T1, when we should start renewing.
+    pub renewal: Option<Duration>,
+    pub broadcast: Option<Ipv4Addr>,
+    pub obtained_at: Instant,
+    /// The hardware address the lease was bound to.
+    pub client_mac: MacAddr,
+    /// Hardware address the ACK was sent from.
+    pub server_mac: MacAddr,
+}
+
+impl Lease {
+    pub fn from_ack(
+        ack: &v4::Message,
+        client_mac: MacAddr,
+        server_mac: MacAddr,
+    ) -> Result<Lease, Error> {
+        let opts = ack.opts();
+        let server_id = match opts.get(v4::OptionCode::ServerIdentifier) {
+            Some(v4::DhcpOption::ServerIdentifier(id)) => *id,
+            _ => ack.siaddr(),
+        };
+        if ack.yiaddr().is_unspecified() || server_id.is_unspecified() {
+            return Err(Error::Dhcp(DhcpError::Malformed(
+                "ACK has no address or server identifier".to_string(),
+            )));
+        }
+
+        Ok(Lease {
+            address: ack.yiaddr(),
+            netmask: match opts.get(v4::OptionCode::SubnetMask) {
+                Some(v4::DhcpOption::SubnetMask(mask)) => *mask,
+                // Without a mask, only the address itself is on-link.
+                _ => Ipv4Addr::new(255, 255, 255, 255),
+            },
+            routers: match opts.get(v4::OptionCode::Router) {
+                Some(v4::DhcpOption::Router(routers)) => routers.clone(),
+                _ => Vec::new(),
+            },
+            dns: match opts.get(v4::OptionCode::DomainNameServer) {
+                Some(v4::DhcpOption::DomainNameServer(dns)) => dns.clone(),
+                _ => Vec::new(),
+            },
+            server_id,
+            lease_time: match opts.get(v4::OptionCode::AddressLeaseTime) {
+                Some(v4::DhcpOption::AddressLeaseTime(secs)) => {
+                    Some(Duration::from_secs(*secs as u64))
+                }
+                _ => None,
+            },
+            renewal: match opts.get(v4::OptionCode::Renewal) {
+                Some(v4::DhcpOption::Renewal(secs)) => Some(Duration::from_secs(*secs as u64)),
+                _ => None,
+            },
+            broadcast: match opts.get(v4::OptionCode::BroadcastAddr) {
+                Some(v4::DhcpOption::BroadcastAddr(broadcast)) => Some(*broadcast),
+                _ => None,
+            },
+            obtained_at: Instant::now(),
+            client_mac,
+            server_mac,
+        })
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        u32::from(self.netmask).leading_ones() as u8
+    }
+
+    pub fn gateway(&self) -> Option<Ipv4Addr> {
+        self.routers.first().copied()
+    }
+
+    /// The leased address as it should be installed on `iface_idx`. It expires with the
+    /// lease, so the kernel drops it if we fail to renew. An expired lease has no address to
+    /// install: the kernel refuses a lifetime of 0.
+    pub fn to_address(&self, iface_idx: u32) -> Result<Address, Error> {
+        if self.expired() {
+            return Err(Error::InvalidArgument(format!(
+                "the lease for {} has expired",
+                self.address
+            )));
+        }
+        let mut address = Address::new(iface_idx, IpAddr::V4(self.address), self.prefix_len());
+        address.broadcast = self.broadcast;
+        address.valid_lifetime = self.lease_time.map(|lease_time| {
+            let left = lease_time.saturating_sub(self.obtained_at.elapsed());
+            // Less than a second left still counts.
+            left.as_secs().clamp(1, u32::MAX as u64) as u32
+        });
+        Ok(address)
+    }
+
+    /// How long until the lease should be renewed: at T1 (half the lease time if the server
+    /// sent none), and after a failed attempt when half of what is left has passed, a minute
+    /// at least (RFC 2131 4.4.5). `None` for an infinite or expired lease.
+    pub fn next_renewal(&self) -> Option<Duration> {
+        let lease_time = self.lease_time?;
+        let elapsed = self.obtained_at.elapsed();
+        let renewal = self.renewal.unwrap_or(lease_time / 2);
+        if elapsed < renewal {
+            return Some(renewal - elapsed);
+        }
+        let left = lease_time
+            .checked_sub(elapsed)
+            .filter(|left| !left.is_zero())?;
+        Some((left / 2).max(Duration::from_secs(60)).min(left))
+    }
+
+    pub fn expired(&self) -> bool {
+        match self.lease_time {
+            Some(lease_time) => self.obtained_at.elapsed() >= lease_time,
+            None => false,
+        }
+    }
+}
+
+/// Configure the leased address and default route on an interface. Applying a renewed lease
+/// again extends the lifetime of the address.
+pub async fn apply_lease(handle: &Handle, iface_idx: u32, lease: &Lease) -> Result<(), Error> {
+    replace_address(handle, &lease.to_address(iface_idx)?).await?;
+
+    if let Some(gateway) = lease.gateway() {
+        set_default_route(handle, iface_idx, gateway).await?;
+    }
+    Ok(())
+}
+
+/// Remove the leased address from an interface. Routes through it go with it.
+pub async fn remove_lease(handle: &Handle, iface_idx: u32, lease: &Lease) -> Result<(), Error> {
+    let addresses = get_addresses(handle, iface_idx).await?;
+
+    match addresses
+        .iter()
+        .find(|address| address.address == IpAddr::V4(lease.address))
+    {
+        Some(address) => Ok(del_address(handle, address).await?),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test_lease {
+    use super::*;
+
+    fn lease(lease_time: Option<u64>, age: u64) -> Lease {
+        Lease {
+            address: Ipv4Addr::new(192, 168, 7, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            routers: Vec::new(),
+            dns: Vec::new(),
+            server_id: Ipv4Addr::new(192, 168, 7, 1),
+            lease_time: lease_time.map(Duration::from_secs),
+            renewal: None,
+            broadcast: None,
+            obtained_at: Instant::now() - Duration::from_secs(age),
+            client_mac: MacAddr::zero(),
+            server_mac: MacAddr::zero(),
+        }
+    }
+
+    // Whole seconds, as the clock moves on while testing.
+    fn next_renewal(lease: &Lease) -> Option<u64> {
+        lease
+            .next_renewal()
+            .map(|wait| wait.as_secs_f64().round() as u64)
+    }
+
+    #[test]
+    fn renew_at_t1_then_halfway_to_expiry() {
+        assert_eq!(next_renewal(&lease(None, 0)), None);
+        // T1 defaults to half the lease time.
+        assert_eq!(next_renewal(&lease(Some(3600), 0)), Some(1800));
+        let mut with_t1 = lease(Some(3600), 0);
+        with_t1.renewal = Some(Duration::from_secs(600));
+        assert_eq!(next_renewal(&with_t1), Some(600));
+
+        // Past T1 every retry waits half of what is left, a minute at least.
+        assert_eq!(next_renewal(&lease(Some(3600), 3000)), Some(300));
+        assert_eq!(next_renewal(&lease(Some(3600), 3500)), Some(60));
+        assert_eq!(next_renewal(&lease(Some(3600), 3590)), Some(10));
+        assert_eq!(next_renewal(&lease(Some(3600), 4000)), None);
+    }
+}
@@ -1,47 +1,393 @@
 use futures::TryStreamExt;
-use netlink_packet_route::AddressMessage;
+use log::debug;
+use netlink_packet_route::address::Nla;
+use netlink_packet_route::{
+    AddressMessage, AF_INET, AF_INET6, IFA_F_DEPRECATED, IFA_F_PERMANENT, IFA_F_SECONDARY,
+    IFA_F_TENTATIVE,
+};
 use rtnetlink::Handle;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug)]
-pub enum RTNetlinkError {
-    RTNetlink(rtnetlink::Error),
-    IOError(std::io::Error),
-    ValidationFailed,
+use crate::utils::run_all;
+use crate::{RTNetlinkError, Scope};
+
+/// An address as the kernel reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub iface_idx: u32,
+    pub address: IpAddr,
+    pub prefix_len: u8,
+    pub scope: Scope,
+    /// IFA_F_* flags.
+    pub flags: u32,
+    pub label: Option<String>,
+    pub broadcast: Option<Ipv4Addr>,
+    /// Seconds left. `None` is forever.
+    pub valid_lifetime: Option<u32>,
+    pub preferred_lifetime: Option<u32>,
 }
 
-pub async fn add_address(
-    handle: &Handle,
-    iface_idx: u32,
-    address: IpAddr,
-    prefix_len: u8,
-) -> Result<(), RTNetlinkError> {
-    let request = handle.address().add(iface_idx, address, prefix_len);
+impl Address {
+    /// A permanent address with the defaults of `ip address add`. Set the other fields to
+    /// give it a lifetime, flags, a label or a broadcast address other than the usual one.
+    pub fn new(iface_idx: u32, address: IpAddr, prefix_len: u8) -> Address {
+        Address {
+            iface_idx,
+            address,
+            prefix_len,
+            scope: Scope::Universe,
+            flags: 0,
+            label: None,
+            broadcast: None,
+            valid_lifetime: None,
+            preferred_lifetime: None,
+        }
+    }
 
-    request.execute().await.map_err(RTNetlinkError::RTNetlink)?;
+    /// Read an address message. Messages without an address are not addresses we can use.
+    pub fn from_message(message: &AddressMessage) -> Option<Address> {
+        let mut local = None;
+        let mut peer = None;
+        let mut address = Address {
+            iface_idx: message.header.index,
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            prefix_len: message.header.prefix_len,
+            scope: Scope::from(message.header.scope),
+            flags: message.header.flags as u32,
+            label: None,
+            broadcast: None,
+            valid_lifetime: None,
+            preferred_lifetime: None,
+        };
 
-    // Validate:
-    let res_address = handle
+        for nla in &message.nlas {
+            match nla {
+                Nla::Local(bytes) => local = ip_from_bytes(bytes),
+                Nla::Address(bytes) => peer = ip_from_bytes(bytes),
+                Nla::Label(label) => address.label = Some(label.clone()),
+                Nla::Broadcast(bytes) => {
+                    address.broadcast = match ip_from_bytes(bytes) {
+                        Some(IpAddr::V4(broadcast)) => Some(broadcast),
+                        _ => None,
+                    }
+                }
+                // The header only has room for the first 8 flags.
+                Nla::Flags(flags) => address.flags = *flags,
+                Nla::CacheInfo(bytes) if bytes.len() >= 8 => {
+                    let lifetime = |at: usize| {
+                        let mut buf = [0u8; 4];
+                        buf.copy_from_slice(&bytes[at..at + 4]);
+                        match u32::from_ne_bytes(buf) {
+                            u32::MAX => None,
+                            seconds => Some(seconds),
+                        }
+                    };
+                    address.preferred_lifetime = lifetime(0);
+                    address.valid_lifetime = lifetime(4);
+                }
+                _ => {}
+            }
+        }
+
+        // On point-to-point links IFA_ADDRESS is the peer; IFA_LOCAL is ours.
+        address.address = local.or(peer)?;
+        Some(address)
+    }
+
+    /// `AF_INET` or `AF_INET6`.
+    pub fn family(&self) -> u8 {
+        match self.address {
+            IpAddr::V4(_) => AF_INET as u8,
+            IpAddr::V6(_) => AF_INET6 as u8,
+        }
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.flags & IFA_F_PERMANENT != 0
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.flags & IFA_F_SECONDARY != 0
+    }
+
+    /// IPv6 duplicate address detection has not finished yet.
+    pub fn is_tentative(&self) -> bool {
+        self.flags & IFA_F_TENTATIVE != 0
+    }
+
+    pub fn is_deprecated(&self) -> bool {
+        self.flags & IFA_F_DEPRECATED != 0
+    }
+}
+
+pub(crate) fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(bytes);
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(bytes);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) async fn address_messages(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<Vec<AddressMessage>, RTNetlinkError> {
+    handle
         .address()
         .get()
         .set_link_index_filter(iface_idx)
-        .set_address_filter(address)
         .execute()
-        .try_next()
+        .try_collect()
         .await
-        .map_err(RTNetlinkError::RTNetlink)?;
+        .map_err(RTNetlinkError::from)
+}
 
-    match res_address {
-        Some(_) => Ok(()),
-        None => Err(RTNetlinkError::ValidationFailed),
+/// Get the addresses (both families) of an interface.
+pub async fn get_addresses(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<Vec<Address>, RTNetlinkError> {
+    let messages = address_messages(handle, iface_idx).await?;
+    Ok(messages.iter().filter_map(Address::from_message).collect())
+}
+
+// The attributes `AddressAddRequest` doesn't set itself.
+fn extra_nlas(address: &Address) -> Result<Vec<Nla>, RTNetlinkError> {
+    let mut nlas = Vec::new();
+    // The header only has room for the first 8 flags.
+    if address.flags != 0 {
+        nlas.push(Nla::Flags(address.flags));
+    }
+    if let Some(label) = &address.label {
+        nlas.push(Nla::Label(label.clone()));
+    }
+    if address.valid_lifetime == Some(0) {
+        return Err(RTNetlinkError::invalid(format!(
+            "valid lifetime of 0 for {}",
+            address.address
+        )));
     }
+    if address.valid_lifetime.is_some() || address.preferred_lifetime.is_some() {
+        let forever = |lifetime: Option<u32>| lifetime.unwrap_or(u32::MAX).to_ne_bytes();
+        let valid = forever(address.valid_lifetime);
+        let preferred = match address.preferred_lifetime {
+            // Without a preferred lifetime, prefer it for as long as it is valid.
+            None => valid,
+            Some(_) => forever(address.preferred_lifetime),
+        };
+        if u32::from_ne_bytes(preferred) > u32::from_ne_bytes(valid) {
+            return Err(RTNetlinkError::invalid(format!(
+                "preferred lifetime {:?} longer than valid lifetime {:?}",
+                address.preferred_lifetime, address.valid_lifetime
+            )));
+        }
+        // struct ifa_cacheinfo; the timestamps are ignored when adding.
+        nlas.push(Nla::CacheInfo([preferred, valid, [0; 4], [0; 4]].concat()));
+    }
+    Ok(nlas)
 }
 
-pub async fn del_address(handle: &Handle, address: AddressMessage) -> Result<(), RTNetlinkError> {
-    let request = handle.address().del(address);
-    request.execute().await.map_err(RTNetlinkError::RTNetlink)?;
+async fn send_address(
+    handle: &Handle,
+    address: &Address,
+    replace: bool,
+) -> Result<(), RTNetlinkError> {
+    let mut request = handle
+        .address()
+        .add(address.iface_idx, address.address, address.prefix_len);
+    if replace {
+        request = request.replace();
+    }
 
-    // Validation cannot be done at this moment because nlas feilds are private. We will have to
-    // manually modify this package to to validation.
+    let message = request.message_mut();
+    message.header.scope = address.scope.into();
+    message.header.flags = address.flags as u8;
+    match (address.broadcast, address.address) {
+        (Some(broadcast), IpAddr::V4(_)) => {
+            message.nlas.retain(|nla| !matches!(nla, Nla::Broadcast(_)));
+            message
+                .nlas
+                .push(Nla::Broadcast(broadcast.octets().to_vec()));
+        }
+        (Some(broadcast), IpAddr::V6(_)) => {
+            return Err(RTNetlinkError::invalid(format!(
+                "broadcast {} for IPv6 address {}",
+                broadcast, address.address
+            )))
+        }
+        (None, _) => {}
+    }
+    message.nlas.extend(extra_nlas(address)?);
+
+    request.execute().await.map_err(RTNetlinkError::from)
+}
+
+async fn install_address(
+    handle: &Handle,
+    address: &Address,
+    replace: bool,
+) -> Result<(), RTNetlinkError> {
+    send_address(handle, address, replace).await?;
+
+    // Validate:
+    let addresses = get_addresses(handle, address.iface_idx).await?;
+    match addresses.iter().any(|found| same_address(found, address)) {
+        true => Ok(()),
+        false => Err(RTNetlinkError::validation(
+            format!(
+                "{}/{} on link {}",
+                address.address, address.prefix_len, address.iface_idx
+            ),
+            "no such address",
+        )),
+    }
+}
+
+/// Add an address to its interface. Fails if it is already there.
+pub async fn add_address(handle: &Handle, address: &Address) -> Result<(), RTNetlinkError> {
+    install_address(handle, address, false).await
+}
+
+/// Add an address, or refresh the lifetimes of the one already there (e.g. on lease
+/// renewal). The kernel keeps the flags an IPv4 address was added with.
+pub async fn replace_address(handle: &Handle, address: &Address) -> Result<(), RTNetlinkError> {
+    install_address(handle, address, true).await
+}
+
+/// Add several addresses and check them all with one dump per interface at the end.
+pub async fn add_addresses(handle: &Handle, addresses: &[Address]) -> Result<(), RTNetlinkError> {
+    run_all(addresses, |address| send_address(handle, address, false)).await?;
+
+    // Validate:
+    let mut links: Vec<u32> = addresses.iter().map(|address| address.iface_idx).collect();
+    links.sort_unstable();
+    links.dedup();
+    for iface_idx in links {
+        let found = get_addresses(handle, iface_idx).await?;
+        let missing = addresses.iter().find(|address| {
+            address.iface_idx == iface_idx && !found.iter().any(|f| same_address(f, address))
+        });
+        if let Some(address) = missing {
+            return Err(RTNetlinkError::validation(
+                format!(
+                    "{}/{} on link {}",
+                    address.address, address.prefix_len, iface_idx
+                ),
+                "no such address",
+            ));
+        }
+    }
     Ok(())
 }
+
+fn same_address(a: &Address, b: &Address) -> bool {
+    a.iface_idx == b.iface_idx && a.address == b.address && a.prefix_len == b.prefix_len
+}
+
+/// Remove an address from its interface.
+pub async fn del_address(handle: &Handle, address: &Address) -> Result<(), RTNetlinkError> {
+    // Delete with the message the kernel gave us, so every attribute it matches on is right.
+    let messages = address_messages(handle, address.iface_idx).await?;
+    let message = messages.into_iter().find(|message| {
+        Address::from_message(message).is_some_and(|found| same_address(&found, address))
+    });
+    match message {
+        Some(message) => {
+            let request = handle.address().del(message);
+            request.execute().await.map_err(RTNetlinkError::from)?;
+        }
+        None => debug!(
+            "{}/{} is not on link {}",
+            address.address, address.prefix_len, address.iface_idx
+        ),
+    }
+
+    // Validate:
+    let addresses = get_addresses(handle, address.iface_idx).await?;
+    match addresses.iter().any(|found| same_address(found, address)) {
+        true => Err(RTNetlinkError::validation(
+            format!(
+                "no {}/{} on link {}",
+                address.address, address.prefix_len, address.iface_idx
+            ),
+            "address still present",
+        )),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test_address {
+    use super::*;
+
+    #[test]
+    fn from_message_prefers_local() {
+        let mut message = AddressMessage::default();
+        message.header.family = AF_INET as u8;
+        message.header.index = 3;
+        message.header.prefix_len = 24;
+        message.header.scope = Scope::Universe.into();
+        message.nlas = vec![
+            Nla::Address(vec![10, 0, 0, 2]),
+            Nla::Local(vec![10, 0, 0, 1]),
+            Nla::Label("eth0".to_string()),
+            Nla::Flags(IFA_F_PERMANENT),
+            // preferred: forever, valid: 60s
+            Nla::CacheInfo([u32::MAX.to_ne_bytes(), 60u32.to_ne_bytes(), [0; 4], [0; 4]].concat()),
+        ];
+
+        let address = Address::from_message(&message).unwrap();
+        assert_eq!(address.address, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(address.iface_idx, 3);
+        assert_eq!(address.scope, Scope::Universe);
+        assert_eq!(address.label.as_deref(), Some("eth0"));
+        assert!(address.is_permanent());
+        assert_eq!(address.preferred_lifetime, None);
+        assert_eq!(address.valid_lifetime, Some(60));
+
+        message.nlas.clear();
+        assert_eq!(Address::from_message(&message), None);
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn add_and_del_address() {
+        use netlink_packet_route::IFA_F_NOPREFIXROUTE;
+
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 7, 2));
+
+        // As a lease would: expiring, and without a prefix route.
+        let mut wanted = Address::new(net.client_idx, ip, 24);
+        wanted.valid_lifetime = Some(600);
+        wanted.preferred_lifetime = Some(300);
+        wanted.flags = IFA_F_NOPREFIXROUTE;
+        add_address(handle, &wanted).await.unwrap();
+        let addresses = get_addresses(handle, net.client_idx).await.unwrap();
+        let address = addresses.iter().find(|a| a.address == ip).unwrap();
+        assert_eq!(address.prefix_len, 24);
+        assert!(!address.is_permanent());
+        assert_eq!(address.flags & IFA_F_NOPREFIXROUTE, IFA_F_NOPREFIXROUTE);
+        assert!(address.preferred_lifetime.is_some_and(|t| t <= 300));
+
+        // Renewed.
+        wanted.valid_lifetime = Some(6000);
+        replace_address(handle, &wanted).await.unwrap();
+        let addresses = get_addresses(handle, net.client_idx).await.unwrap();
+        let address = addresses.iter().find(|a| a.address == ip).unwrap();
+        assert!(address.valid_lifetime.is_some_and(|t| t > 5990));
+
+        del_address(handle, address).await.unwrap();
+        let addresses = get_addresses(handle, net.client_idx).await.unwrap();
+        assert!(addresses.iter().all(|a| a.address != ip));
+    }
+}
@@ -0,0 +1,126 @@
+// rtnetlink handles are cheap to clone and share one socket, so most callers only ever need
+// one connection. `ConnectionManager` owns it (and a few more for work that should run in
+// parallel) and opens a new one when the old one has ended, e.g. because the runtime it was
+// spawned on shut down.
+use futures::channel::mpsc::UnboundedReceiver;
+use log::debug;
+use netlink_packet_core::NetlinkMessage;
+use netlink_packet_route::RtnlMessage;
+use netlink_proto::sys::SocketAddr;
+use rtnetlink::{new_connection, Handle};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::task::JoinHandle;
+
+use crate::{in_netns, NetNs, RTNetlinkError};
+
+/// Connections in the pool of `shared_connections`.
+pub const POOL_SIZE: usize = 4;
+
+pub(crate) type Messages = UnboundedReceiver<(NetlinkMessage<RtnlMessage>, SocketAddr)>;
+
+// Every netlink socket of the crate is opened here. The connection still has to be spawned.
+pub(crate) fn connect(
+    ns: &NetNs,
+) -> Result<(netlink_proto::Connection<RtnlMessage>, Handle, Messages), RTNetlinkError> {
+    Ok(in_netns(ns, new_connection)??)
+}
+
+struct Connection {
+    handle: Handle,
+    task: JoinHandle<()>,
+}
+
+pub struct ConnectionManager {
+    ns: NetNs,
+    // Slot 0 is the primary connection. Slots are filled on first use.
+    pool: Mutex<Vec<Option<Connection>>>,
+    next: AtomicUsize,
+}
+
+impl ConnectionManager {
+    /// Connections to `ns`, `pool_size` of them at most (at least one). Every function
+    /// taking a `Handle` works on `ns` when given one of these.
+    pub fn new(ns: NetNs, pool_size: usize) -> ConnectionManager {
+        ConnectionManager {
+            ns,
+            pool: Mutex::new((0..pool_size.max(1)).map(|_| None).collect()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn netns(&self) -> &NetNs {
+        &self.ns
+    }
+
+    /// A handle on the primary connection. Needs a tokio runtime.
+    pub fn handle(&self) -> Result<Handle, RTNetlinkError> {
+        self.slot(0)
+    }
+
+    /// A handle on the pool connections in turn, for spreading requests that run at the
+    /// same time.
+    pub fn pooled_handle(&self) -> Result<Handle, RTNetlinkError> {
+        let len = self.pool.lock().unwrap().len();
+        self.slot(self.next.fetch_add(1, Ordering::Relaxed) % len)
+    }
+
+    fn slot(&self, index: usize) -> Result<Handle, RTNetlinkError> {
+        let mut pool = self.pool.lock().unwrap();
+        match &pool[index] {
+            Some(connection) if !connection.task.is_finished() => {
+                return Ok(connection.handle.clone())
+            }
+            Some(_) => debug!("Netlink connection {} ended, reconnecting", index),
+            None => {}
+        }
+
+        let (connection, handle, _) = connect(&self.ns)?;
+        let task = tokio::spawn(connection);
+        pool[index] = Some(Connection {
+            handle: handle.clone(),
+            task,
+        });
+        Ok(handle)
+    }
+}
+
+impl Drop for ConnectionManager {
+    fn drop(&mut self) {
+        for connection in self.pool.lock().unwrap().iter().flatten() {
+            connection.task.abort();
+        }
+    }
+}
+
+/// The connections to the namespace of the process that `local_net` uses itself.
+pub fn shared_connections() -> &'static ConnectionManager {
+    static SHARED: OnceLock<ConnectionManager> = OnceLock::new();
+    SHARED.get_or_init(|| ConnectionManager::new(NetNs::Current, POOL_SIZE))
+}
+
+/// A handle on the shared primary connection.
+pub fn shared_handle() -> Result<Handle, RTNetlinkError> {
+    shared_connections().handle()
+}
+
+#[cfg(test)]
+mod test_connection {
+    use super::*;
+    use crate::get_links;
+
+    #[tokio::test]
+    async fn reconnects_after_the_connection_ends() {
+        let manager = ConnectionManager::new(NetNs::Current, 2);
+        get_links(&manager.handle().unwrap()).await.unwrap();
+
+        for connection in manager.pool.lock().unwrap().iter().flatten() {
+            connection.task.abort();
+        }
+        tokio::task::yield_now().await;
+
+        get_links(&manager.handle().unwrap()).await.unwrap();
+        get_links(&manager.pooled_handle().unwrap()).await.unwrap();
+        get_links(&manager.pooled_handle().unwrap()).await.unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+/// Errors returned by every function in `local_net`.
+#[derive(Debug)]
+pub enum RTNetlinkError {
+    /// The kernel refused the request (EPERM/EACCES). Usually means CAP_NET_ADMIN is missing.
+    PermissionDenied,
+    /// The kernel does not know the interface the request referred to (ENODEV).
+    InterfaceNotFound,
+    /// The kernel answered with an errno that is not classified above.
+    Netlink(i32),
+    /// rtnetlink failed without an errno (unexpected message, invalid NLA, ...).
+    RTNetlink(rtnetlink::Error),
+    IOError(std::io::Error),
+    /// The request was accepted, but reading the kernel state back did not match it.
+    ValidationFailed {
+        expected: String,
+        found: String,
+    },
+    /// The caller asked for something that can't be expressed; nothing was sent.
+    InvalidArgument(String),
+}
+
+impl RTNetlinkError {
+    pub(crate) fn validation(expected: impl ToString, found: impl ToString) -> RTNetlinkError {
+        RTNetlinkError::ValidationFailed {
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
+    }
+
+    pub(crate) fn invalid(reason: impl ToString) -> RTNetlinkError {
+        RTNetlinkError::InvalidArgument(reason.to_string())
+    }
+
+    /// The errno the kernel answered with, if any.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            RTNetlinkError::PermissionDenied => Some(libc::EPERM),
+            RTNetlinkError::InterfaceNotFound => Some(libc::ENODEV),
+            RTNetlinkError::Netlink(errno) => Some(*errno),
+            RTNetlinkError::IOError(e) => e.raw_os_error(),
+            _ => None,
+        }
+    }
+}
+
+impl From<rtnetlink::Error> for RTNetlinkError {
+    fn from(error: rtnetlink::Error) -> Self {
+        let errno = match &error {
+            rtnetlink::Error::NetlinkError(message) => message.raw_code().abs(),
+            _ => return RTNetlinkError::RTNetlink(error),
+        };
+
+        match errno {
+            libc::EPERM | libc::EACCES => RTNetlinkError::PermissionDenied,
+            libc::ENODEV => RTNetlinkError::InterfaceNotFound,
+            _ => RTNetlinkError::Netlink(errno),
+        }
+    }
+}
+
+impl From<std::io::Error> for RTNetlinkError {
+    fn from(error: std::io::Error) -> Self {
+        RTNetlinkError::IOError(error)
+    }
+}
+
+impl std::error::Error for RTNetlinkError {}
+
+impl fmt::Display for RTNetlinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RTNetlinkError::PermissionDenied => {
+                write!(f, "Permission denied (CAP_NET_ADMIN required)")
+            }
+            RTNetlinkError::InterfaceNotFound => write!(f, "No such interface"),
+            RTNetlinkError::Netlink(errno) => write!(
+                f,
+                "RTNETLINK answers: {}",
+                std::io::Error::from_raw_os_error(*errno)
+            ),
+            RTNetlinkError::RTNetlink(e) => write!(f, "rtnetlink error: {}", e),
+            RTNetlinkError::IOError(e) => write!(f, "I/O error: {}", e),
+            RTNetlinkError::ValidationFailed { expected, found } => write!(
+                f,
+                "Validation failed: expected {}, found {}",
+                expected, found
+            ),
+            RTNetlinkError::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+        }
+    }
+}
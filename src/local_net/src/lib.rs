@@ -2,10 +2,30 @@
 
 mod address;
 mod address_families;
+mod connection;
+mod error;
+mod link;
+mod monitor;
+mod neighbour;
+mod netns;
 mod route;
+mod rule;
+#[cfg(feature = "testbed")]
+mod testbed;
 mod utils;
+mod virtual_link;
 
 pub use crate::address::*;
 pub use crate::address_families::*;
+pub use crate::connection::*;
+pub use crate::error::*;
+pub use crate::link::*;
+pub use crate::monitor::*;
+pub use crate::neighbour::*;
+pub use crate::netns::*;
 pub use crate::route::*;
+pub use crate::rule::*;
+#[cfg(feature = "testbed")]
+pub use crate::testbed::*;
 pub use crate::utils::*;
+pub use crate::virtual_link::*;
@@ -0,0 +1,552 @@
+use futures::TryStreamExt;
+use log::{debug, warn};
+use netlink_packet_route::link::nlas::{Nla, State};
+use netlink_packet_route::{
+    LinkMessage, IFA_F_DEPRECATED, IFA_F_PERMANENT, IFA_F_SECONDARY, IFA_F_TENTATIVE, IFF_LOWER_UP,
+    IFF_UP, RTPROT_KERNEL,
+};
+use rtnetlink::Handle;
+use std::net::IpAddr;
+
+use crate::{
+    get_addresses, get_routes, replace_address, replace_route, Address, RTNetlinkError, Route,
+    Table,
+};
+
+/// Get the raw link message for an interface.
+pub async fn get_link_message(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<LinkMessage, RTNetlinkError> {
+    handle
+        .link()
+        .get()
+        .match_index(iface_idx)
+        .execute()
+        .try_next()
+        .await
+        .map_err(RTNetlinkError::from)?
+        .ok_or(RTNetlinkError::InterfaceNotFound)
+}
+
+/// Get the index of an interface from its name.
+pub async fn get_link_index(handle: &Handle, name: &str) -> Result<u32, RTNetlinkError> {
+    let link = handle
+        .link()
+        .get()
+        .match_name(name.to_string())
+        .execute()
+        .try_next()
+        .await
+        .map_err(RTNetlinkError::from)?
+        .ok_or(RTNetlinkError::InterfaceNotFound)?;
+
+    Ok(link.header.index)
+}
+
+/// Get the current hardware address of an interface.
+pub async fn get_link_address(handle: &Handle, iface_idx: u32) -> Result<Vec<u8>, RTNetlinkError> {
+    let link = get_link_message(handle, iface_idx).await?;
+
+    link.nlas
+        .into_iter()
+        .find_map(|nla| match nla {
+            Nla::Address(address) => Some(address),
+            _ => None,
+        })
+        .ok_or_else(|| RTNetlinkError::validation("a hardware address", "none"))
+}
+
+/// Get the permanent (burned-in) hardware address of an interface (IFLA_PERM_ADDRESS).
+/// Virtual interfaces and older kernels (< 5.6) don't report one.
+pub async fn get_permanent_address(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<Option<Vec<u8>>, RTNetlinkError> {
+    let link = get_link_message(handle, iface_idx).await?;
+
+    Ok(link.nlas.into_iter().find_map(|nla| match nla {
+        // The kernel reports all zeros when the driver doesn't know it.
+        Nla::PermAddress(address) if address.iter().any(|&b| b != 0) => Some(address),
+        _ => None,
+    }))
+}
+
+/// A link as the kernel reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    pub address: Option<Vec<u8>>,
+    pub mtu: Option<u32>,
+    pub txqueuelen: Option<u32>,
+    pub alias: Option<String>,
+    /// Index of the bridge or bond this link is enslaved to.
+    pub master: Option<u32>,
+    /// Administratively up (IFF_UP).
+    pub up: bool,
+    /// The driver reports carrier (IFF_LOWER_UP).
+    pub lower_up: bool,
+    pub oper_state: State,
+}
+
+impl From<LinkMessage> for Link {
+    fn from(message: LinkMessage) -> Self {
+        let mut link = Link {
+            index: message.header.index,
+            name: String::new(),
+            address: None,
+            mtu: None,
+            txqueuelen: None,
+            alias: None,
+            master: None,
+            up: message.header.flags & IFF_UP != 0,
+            lower_up: message.header.flags & IFF_LOWER_UP != 0,
+            oper_state: State::Unknown,
+        };
+
+        for nla in message.nlas {
+            match nla {
+                Nla::IfName(name) => link.name = name,
+                Nla::Address(address) => link.address = Some(address),
+                Nla::Mtu(mtu) => link.mtu = Some(mtu),
+                Nla::TxQueueLen(len) => link.txqueuelen = Some(len),
+                Nla::IfAlias(alias) => link.alias = Some(alias),
+                Nla::Master(master) => link.master = Some(master),
+                Nla::OperState(state) => link.oper_state = state,
+                _ => {}
+            }
+        }
+        link
+    }
+}
+
+/// Per-link counters (IFLA_STATS64).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+    pub multicast: u64,
+    pub collisions: u64,
+}
+
+impl LinkStats {
+    // rtnl_link_stats64 is a row of native endian u64s, these are the first ten.
+    fn parse(bytes: &[u8]) -> Option<LinkStats> {
+        let field = |i: usize| -> Option<u64> {
+            Some(u64::from_ne_bytes(
+                bytes.get(i * 8..i * 8 + 8)?.try_into().ok()?,
+            ))
+        };
+
+        Some(LinkStats {
+            rx_packets: field(0)?,
+            tx_packets: field(1)?,
+            rx_bytes: field(2)?,
+            tx_bytes: field(3)?,
+            rx_errors: field(4)?,
+            tx_errors: field(5)?,
+            rx_dropped: field(6)?,
+            tx_dropped: field(7)?,
+            multicast: field(8)?,
+            collisions: field(9)?,
+        })
+    }
+}
+
+/// Get every link the kernel knows about.
+pub async fn get_links(handle: &Handle) -> Result<Vec<Link>, RTNetlinkError> {
+    let links: Vec<LinkMessage> = handle
+        .link()
+        .get()
+        .execute()
+        .try_collect()
+        .await
+        .map_err(RTNetlinkError::from)?;
+
+    Ok(links.into_iter().map(Link::from).collect())
+}
+
+/// Get a single link.
+pub async fn get_link(handle: &Handle, iface_idx: u32) -> Result<Link, RTNetlinkError> {
+    Ok(Link::from(get_link_message(handle, iface_idx).await?))
+}
+
+/// Read the counters of a link.
+pub async fn get_link_stats(handle: &Handle, iface_idx: u32) -> Result<LinkStats, RTNetlinkError> {
+    let link = get_link_message(handle, iface_idx).await?;
+
+    link.nlas
+        .iter()
+        .find_map(|nla| match nla {
+            Nla::Stats64(bytes) => LinkStats::parse(bytes),
+            _ => None,
+        })
+        .ok_or_else(|| RTNetlinkError::validation("link statistics", "none"))
+}
+
+// Read the link back and check that `read` gives what we asked for.
+async fn validate_link<T, F>(
+    handle: &Handle,
+    iface_idx: u32,
+    expected: T,
+    read: F,
+) -> Result<(), RTNetlinkError>
+where
+    T: PartialEq + std::fmt::Debug,
+    F: Fn(&Link) -> T,
+{
+    let found = read(&get_link(handle, iface_idx).await?);
+    match found == expected {
+        true => Ok(()),
+        false => Err(RTNetlinkError::validation(
+            format!("{:?} on link {}", expected, iface_idx),
+            format!("{:?}", found),
+        )),
+    }
+}
+
+async fn set_up(handle: &Handle, iface_idx: u32, up: bool) -> Result<(), RTNetlinkError> {
+    let request = handle.link().set(iface_idx);
+    let request = match up {
+        true => request.up(),
+        false => request.down(),
+    };
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    validate_link(handle, iface_idx, up, |link| link.up).await
+}
+
+/// Bring a link administratively up.
+pub async fn set_link_up(handle: &Handle, iface_idx: u32) -> Result<(), RTNetlinkError> {
+    set_up(handle, iface_idx, true).await
+}
+
+/// Bring a link administratively down.
+pub async fn set_link_down(handle: &Handle, iface_idx: u32) -> Result<(), RTNetlinkError> {
+    set_up(handle, iface_idx, false).await
+}
+
+pub async fn set_link_mtu(handle: &Handle, iface_idx: u32, mtu: u32) -> Result<(), RTNetlinkError> {
+    let request = handle.link().set(iface_idx).mtu(mtu);
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    validate_link(handle, iface_idx, Some(mtu), |link| link.mtu).await
+}
+
+/// Rename a link. The kernel refuses (EBUSY) while the link is up.
+pub async fn set_link_name(
+    handle: &Handle,
+    iface_idx: u32,
+    name: &str,
+) -> Result<(), RTNetlinkError> {
+    let request = handle.link().set(iface_idx).name(name.to_string());
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    validate_link(handle, iface_idx, name.to_string(), |link| {
+        link.name.clone()
+    })
+    .await
+}
+
+pub async fn set_link_txqueuelen(
+    handle: &Handle,
+    iface_idx: u32,
+    txqueuelen: u32,
+) -> Result<(), RTNetlinkError> {
+    // rtnetlink has no builder for this one.
+    let mut request = handle.link().set(iface_idx);
+    request.message_mut().nlas.push(Nla::TxQueueLen(txqueuelen));
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    validate_link(handle, iface_idx, Some(txqueuelen), |link| link.txqueuelen).await
+}
+
+/// Set the alias (`ip link set ... alias`). An empty alias removes it.
+pub async fn set_link_alias(
+    handle: &Handle,
+    iface_idx: u32,
+    alias: &str,
+) -> Result<(), RTNetlinkError> {
+    let mut request = handle.link().set(iface_idx);
+    request
+        .message_mut()
+        .nlas
+        .push(Nla::IfAlias(alias.to_string()));
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    let expected = match alias.is_empty() {
+        true => None,
+        false => Some(alias.to_string()),
+    };
+    validate_link(handle, iface_idx, expected, |link| link.alias.clone()).await
+}
+
+/// Set the hardware address of an interface (RTM_SETLINK).
+///
+/// Some drivers refuse to change the address of a running interface (EBUSY). For those the
+/// link is taken down, changed and brought back up, after which the addresses and routes
+/// the kernel dropped in the process are put back.
+pub async fn set_link_address(
+    handle: &Handle,
+    iface_idx: u32,
+    address: &[u8],
+) -> Result<(), RTNetlinkError> {
+    if get_link_address(handle, iface_idx).await? == address {
+        return Ok(());
+    }
+
+    let request = handle.link().set(iface_idx).address(address.to_vec());
+    match request.execute().await.map_err(RTNetlinkError::from) {
+        Ok(()) => {}
+        Err(RTNetlinkError::Netlink(libc::EBUSY)) => {
+            debug!(
+                "Link {} is busy, cycling it to change its address",
+                iface_idx
+            );
+            set_link_address_cycled(handle, iface_idx, address).await?
+        }
+        Err(e) => return Err(e),
+    }
+
+    // Validate:
+    let found = get_link_address(handle, iface_idx).await?;
+    match found == address {
+        true => Ok(()),
+        false => Err(RTNetlinkError::validation(
+            format_hw_address(address),
+            format_hw_address(&found),
+        )),
+    }
+}
+
+async fn set_link_address_cycled(
+    handle: &Handle,
+    iface_idx: u32,
+    address: &[u8],
+) -> Result<(), RTNetlinkError> {
+    let was_up = get_link_message(handle, iface_idx).await?.header.flags & IFF_UP != 0;
+    let addresses = get_addresses(handle, iface_idx).await?;
+    let routes = get_link_routes(handle, iface_idx).await?;
+
+    set_up(handle, iface_idx, false).await?;
+    let res = handle
+        .link()
+        .set(iface_idx)
+        .address(address.to_vec())
+        .execute()
+        .await
+        .map_err(RTNetlinkError::from);
+
+    // Even if the change failed, the link has to come back the way we found it, with what
+    // the kernel dropped while it was down. The first error wins.
+    let up = match was_up {
+        true => set_up(handle, iface_idx, true).await,
+        false => Ok(()),
+    };
+    let restored_addresses = restore_addresses(handle, iface_idx, addresses).await;
+    let restored_routes = restore_link_routes(handle, iface_idx, routes).await;
+    res.and(up).and(restored_addresses).and(restored_routes)
+}
+
+/// Get the routes (both families, all tables) that go out through an interface.
+pub async fn get_link_routes(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<Vec<Route>, RTNetlinkError> {
+    let routes = get_routes(handle).await?;
+    Ok(routes
+        .into_iter()
+        .filter(|route| route.iface_idx == Some(iface_idx))
+        .collect())
+}
+
+/// Re-add addresses from an earlier `get_addresses` that are no longer present. IPv6
+/// link-local addresses are skipped since the kernel regenerates them from the MAC. Tries
+/// every address and returns the first failure.
+pub async fn restore_addresses(
+    handle: &Handle,
+    iface_idx: u32,
+    addresses: Vec<Address>,
+) -> Result<(), RTNetlinkError> {
+    let current = get_addresses(handle, iface_idx).await?;
+
+    let mut first_error = None;
+    for mut address in addresses {
+        if is_link_local_v6(&address.address) {
+            continue;
+        }
+        if current
+            .iter()
+            .any(|c| c.address == address.address && c.prefix_len == address.prefix_len)
+        {
+            continue;
+        }
+
+        debug!(
+            "Restoring address {}/{} on link {}",
+            address.address, address.prefix_len, iface_idx
+        );
+        // Flags the kernel sets itself, not ones it accepts.
+        address.flags &= !(IFA_F_SECONDARY | IFA_F_PERMANENT | IFA_F_TENTATIVE | IFA_F_DEPRECATED);
+        if let Err(e) = replace_address(handle, &address).await {
+            warn!("Failed to restore address on link {}: {}", iface_idx, e);
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Re-add routes from an earlier `get_link_routes` that are no longer present. Kernel routes
+/// are skipped since the kernel recreates them with their addresses. Tries every route and
+/// returns the first failure.
+pub async fn restore_link_routes(
+    handle: &Handle,
+    iface_idx: u32,
+    routes: Vec<Route>,
+) -> Result<(), RTNetlinkError> {
+    let current = get_link_routes(handle, iface_idx).await?;
+
+    let mut first_error = None;
+    for route in routes {
+        // Kernel routes come back with their addresses.
+        if route.protocol == RTPROT_KERNEL || route.table == Table::Local {
+            continue;
+        }
+        if current.iter().any(|c| c.same_destination(&route)) {
+            continue;
+        }
+
+        debug!(
+            "Restoring route {}/{} on link {}",
+            route.destination, route.prefix_len, iface_idx
+        );
+        if let Err(e) = replace_route(handle, route).await {
+            // A route that depends on another missing route can fail; keep going with the rest.
+            warn!("Failed to restore route on link {}: {}", iface_idx, e);
+            first_error.get_or_insert(e);
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn is_link_local_v6(address: &IpAddr) -> bool {
+    match address {
+        IpAddr::V6(address) => address.segments()[0] & 0xffc0 == 0xfe80,
+        IpAddr::V4(_) => false,
+    }
+}
+
+fn format_hw_address(address: &[u8]) -> String {
+    address
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod test_link {
+    use super::*;
+
+    #[test]
+    fn parse_link_stats() {
+        let bytes: Vec<u8> = (1..=12u64).flat_map(u64::to_ne_bytes).collect();
+        let stats = LinkStats::parse(&bytes).unwrap();
+        assert_eq!(stats.rx_packets, 1);
+        assert_eq!(stats.tx_bytes, 4);
+        assert_eq!(stats.tx_dropped, 8);
+        assert_eq!(stats.collisions, 10);
+        // Shorter than the ten fields we read.
+        assert_eq!(LinkStats::parse(&bytes[..9 * 8]), None);
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn set_link_attributes() {
+        use crate::TestNet;
+
+        let net = TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let idx = net.client_idx;
+
+        // Renaming needs the link down.
+        set_link_down(handle, idx).await.unwrap();
+        assert!(!get_link(handle, idx).await.unwrap().up);
+
+        set_link_name(handle, idx, "uplink0").await.unwrap();
+        set_link_mtu(handle, idx, 1400).await.unwrap();
+        set_link_txqueuelen(handle, idx, 500).await.unwrap();
+        set_link_alias(handle, idx, "test uplink").await.unwrap();
+        set_link_up(handle, idx).await.unwrap();
+
+        let links = get_links(handle).await.unwrap();
+        let link = links.iter().find(|link| link.index == idx).unwrap();
+        assert!(link.up);
+        assert_eq!(link.name, "uplink0");
+        assert_eq!(link.mtu, Some(1400));
+        assert_eq!(link.txqueuelen, Some(500));
+        assert_eq!(link.alias.as_deref(), Some("test uplink"));
+
+        set_link_alias(handle, idx, "").await.unwrap();
+        assert_eq!(get_link(handle, idx).await.unwrap().alias, None);
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn restore_addresses_and_routes() {
+        use crate::{add_address, add_route, del_address, TestNet};
+        use std::net::Ipv4Addr;
+
+        let net = TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let address = Address::new(net.client_idx, Ipv4Addr::new(192, 168, 7, 2).into(), 24);
+        add_address(handle, &address).await.unwrap();
+        let gateway = Some(Ipv4Addr::new(192, 168, 7, 1).into());
+        let route = Route::new(
+            net.client_idx,
+            Ipv4Addr::new(10, 7, 0, 0).into(),
+            16,
+            gateway,
+        );
+        add_route(handle, route.clone()).await.unwrap();
+
+        let addresses = get_addresses(handle, net.client_idx).await.unwrap();
+        let routes = get_link_routes(handle, net.client_idx).await.unwrap();
+        // Deleting the address takes the routes through it along.
+        del_address(handle, &address).await.unwrap();
+        assert!(get_link_routes(handle, net.client_idx)
+            .await
+            .unwrap()
+            .iter()
+            .all(|found| !found.same_destination(&route)));
+
+        restore_addresses(handle, net.client_idx, addresses)
+            .await
+            .unwrap();
+        restore_link_routes(handle, net.client_idx, routes)
+            .await
+            .unwrap();
+        let found = get_addresses(handle, net.client_idx).await.unwrap();
+        assert!(found.iter().any(|found| found.address == address.address));
+        let found = get_link_routes(handle, net.client_idx).await.unwrap();
+        assert!(found.iter().any(|found| found.same_destination(&route)));
+    }
+}
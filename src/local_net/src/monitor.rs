@@ -0,0 +1,247 @@
+// Kernel notifications for changes made by anyone, not just us. The monitor opens its own
+// netlink socket bound to the multicast groups below and turns the raw messages into events.
+use futures::{Stream, StreamExt};
+use log::{debug, warn};
+use netlink_packet_core::NetlinkPayload;
+use netlink_packet_route::RtnlMessage;
+use netlink_proto::sys::{AsyncSocket, SocketAddr};
+use rtnetlink::constants::{
+    RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_LINK, RTMGRP_NEIGH,
+};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::task::JoinHandle;
+
+use crate::connection::{connect, Messages};
+use crate::{get_links, Address, Link, Neighbour, NetNs, RTNetlinkError, Route};
+
+const GROUPS: u32 =
+    RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR | RTMGRP_IPV4_ROUTE | RTMGRP_NEIGH;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NetEvent {
+    LinkAdded(Link),
+    LinkRemoved(Link),
+    /// Administratively up (IFF_UP set).
+    LinkUp(Link),
+    LinkDown(Link),
+    /// The link has carrier (IFF_LOWER_UP set), e.g. a cable was plugged in.
+    CarrierUp(Link),
+    CarrierDown(Link),
+    AddressAdded(Address),
+    AddressRemoved(Address),
+    RouteAdded(Route),
+    RouteRemoved(Route),
+    /// A neighbour entry was added or changed state.
+    NeighbourChanged(Neighbour),
+    NeighbourRemoved(Neighbour),
+}
+
+/// Stream of `NetEvent`s. Stops listening when dropped.
+pub struct Monitor {
+    connection: JoinHandle<()>,
+    messages: Messages,
+    // Last state seen per link, to tell up/down and carrier changes apart.
+    links: HashMap<u32, Link>,
+    pending: VecDeque<NetEvent>,
+}
+
+impl Monitor {
+    /// Subscribe to link, address (v4 and v6), IPv4 route and neighbour changes.
+    ///
+    /// Links that exist when subscribing are not reported as added. A link that changes while
+    /// they are being listed may already show the change in the listing, and that transition
+    /// (LinkAdded, LinkUp, CarrierUp, ...) is then not reported.
+    pub async fn subscribe() -> Result<Monitor, RTNetlinkError> {
+        Monitor::subscribe_in(&NetNs::Current).await
+    }
+
+    /// Like `subscribe`, for the changes in another namespace.
+    pub async fn subscribe_in(ns: &NetNs) -> Result<Monitor, RTNetlinkError> {
+        let (mut connection, handle, messages) = connect(ns)?;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(0, GROUPS))?;
+        let connection = tokio::spawn(connection);
+
+        // Links that already exist are not reported as added. Bound before listing them, so
+        // no change is lost, but notifications queued while listing are compared with a
+        // snapshot that may already include them and then report no transition.
+        let links = match get_links(&handle).await {
+            Ok(links) => links,
+            Err(e) => {
+                connection.abort();
+                return Err(e);
+            }
+        };
+
+        Ok(Monitor {
+            connection,
+            messages,
+            links: links.into_iter().map(|link| (link.index, link)).collect(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn handle(&mut self, message: RtnlMessage) {
+        let event = match message {
+            RtnlMessage::NewLink(message) => return self.link_changed(Link::from(message)),
+            RtnlMessage::DelLink(message) => {
+                let link = Link::from(message);
+                self.links.remove(&link.index);
+                NetEvent::LinkRemoved(link)
+            }
+            RtnlMessage::NewAddress(message) => match Address::from_message(&message) {
+                Some(address) => NetEvent::AddressAdded(address),
+                None => return,
+            },
+            RtnlMessage::DelAddress(message) => match Address::from_message(&message) {
+                Some(address) => NetEvent::AddressRemoved(address),
+                None => return,
+            },
+            RtnlMessage::NewRoute(message) => match Route::from_message(&message) {
+                Some(route) => NetEvent::RouteAdded(route),
+                None => return,
+            },
+            RtnlMessage::DelRoute(message) => match Route::from_message(&message) {
+                Some(route) => NetEvent::RouteRemoved(route),
+                None => return,
+            },
+            RtnlMessage::NewNeighbour(message) => match Neighbour::from_message(&message) {
+                Some(neighbour) => NetEvent::NeighbourChanged(neighbour),
+                None => return,
+            },
+            RtnlMessage::DelNeighbour(message) => match Neighbour::from_message(&message) {
+                Some(neighbour) => NetEvent::NeighbourRemoved(neighbour),
+                None => return,
+            },
+            other => {
+                debug!("Ignoring netlink notification: {:?}", other);
+                return;
+            }
+        };
+        self.pending.push_back(event);
+    }
+
+    // The kernel sends NewLink for every change (MTU, name, stats, ...), so compare with
+    // what we saw last and only report the transitions.
+    fn link_changed(&mut self, link: Link) {
+        let previous = self.links.insert(link.index, link.clone());
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return self.pending.push_back(NetEvent::LinkAdded(link)),
+        };
+
+        if previous.up != link.up {
+            self.pending.push_back(match link.up {
+                true => NetEvent::LinkUp(link.clone()),
+                false => NetEvent::LinkDown(link.clone()),
+            });
+        }
+        if previous.lower_up != link.lower_up {
+            self.pending.push_back(match link.lower_up {
+                true => NetEvent::CarrierUp(link),
+                false => NetEvent::CarrierDown(link),
+            });
+        }
+    }
+}
+
+impl Stream for Monitor {
+    type Item = NetEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<NetEvent>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+
+            let (message, _) = match self.messages.poll_next_unpin(cx) {
+                Poll::Ready(Some(message)) => message,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            match message.payload {
+                NetlinkPayload::InnerMessage(message) => self.handle(message),
+                NetlinkPayload::Error(e) => warn!("Netlink notification error: {:?}", e),
+                // The socket buffer overflowed; some notifications were lost.
+                NetlinkPayload::Overrun(_) => warn!("Netlink notifications overran"),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.connection.abort();
+    }
+}
+
+#[cfg(test)]
+mod test_monitor {
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn report_link_and_address_changes() {
+        use super::*;
+        use crate::{add_address, set_link_down, set_link_up, TestNet};
+        use std::net::Ipv4Addr;
+        use std::time::Duration;
+
+        // Skip unrelated events (routes, IPv6 link-local addresses, ...) until one matches.
+        async fn expect(monitor: &mut Monitor, wanted: impl Fn(&NetEvent) -> bool) -> NetEvent {
+            loop {
+                let event = tokio::time::timeout(Duration::from_secs(5), monitor.next())
+                    .await
+                    .expect("no matching event")
+                    .unwrap();
+                if wanted(&event) {
+                    return event;
+                }
+            }
+        }
+
+        let net = TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let idx = net.client_idx;
+        let mut monitor = Monitor::subscribe_in(&net.client).await.unwrap();
+
+        // A veth loses carrier when either end goes down.
+        set_link_down(handle, idx).await.unwrap();
+        expect(
+            &mut monitor,
+            |event| matches!(event, NetEvent::LinkDown(link) if link.index == idx),
+        )
+        .await;
+        expect(
+            &mut monitor,
+            |event| matches!(event, NetEvent::CarrierDown(link) if link.index == idx),
+        )
+        .await;
+        set_link_up(handle, idx).await.unwrap();
+        expect(
+            &mut monitor,
+            |event| matches!(event, NetEvent::LinkUp(link) if link.index == idx),
+        )
+        .await;
+        expect(
+            &mut monitor,
+            |event| matches!(event, NetEvent::CarrierUp(link) if link.index == idx),
+        )
+        .await;
+
+        let address = Address::new(idx, Ipv4Addr::new(192, 168, 7, 2).into(), 24);
+        add_address(handle, &address).await.unwrap();
+        let event = expect(&mut monitor, |event| {
+            matches!(event, NetEvent::AddressAdded(found) if found.address == address.address)
+        })
+        .await;
+        let NetEvent::AddressAdded(found) = event else {
+            unreachable!()
+        };
+        assert_eq!(found.iface_idx, idx);
+        assert_eq!(found.prefix_len, 24);
+    }
+}
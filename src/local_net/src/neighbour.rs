@@ -0,0 +1,433 @@
+use futures::TryStreamExt;
+use netlink_packet_route::neighbour::Nla;
+use netlink_packet_route::{
+    NeighbourMessage, NTF_PROXY, NTF_ROUTER, NUD_DELAY, NUD_FAILED, NUD_INCOMPLETE, NUD_NOARP,
+    NUD_NONE, NUD_PERMANENT, NUD_PROBE, NUD_REACHABLE, NUD_STALE,
+};
+use rtnetlink::Handle;
+use std::net::IpAddr;
+
+use crate::address::ip_from_bytes;
+use crate::RTNetlinkError;
+
+/// State of a neighbour entry (NUD_*).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NeighbourState {
+    /// Resolution in progress.
+    Incomplete,
+    Reachable,
+    /// Not confirmed recently; used, but will be probed.
+    Stale,
+    Delay,
+    Probe,
+    Failed,
+    /// No resolution needed (point-to-point, loopback, multicast).
+    NoArp,
+    /// Static entry, never expires.
+    Permanent,
+    None,
+    Other(u16),
+}
+
+impl From<u16> for NeighbourState {
+    fn from(state: u16) -> Self {
+        match state {
+            NUD_INCOMPLETE => NeighbourState::Incomplete,
+            NUD_REACHABLE => NeighbourState::Reachable,
+            NUD_STALE => NeighbourState::Stale,
+            NUD_DELAY => NeighbourState::Delay,
+            NUD_PROBE => NeighbourState::Probe,
+            NUD_FAILED => NeighbourState::Failed,
+            NUD_NOARP => NeighbourState::NoArp,
+            NUD_PERMANENT => NeighbourState::Permanent,
+            NUD_NONE => NeighbourState::None,
+            other => NeighbourState::Other(other),
+        }
+    }
+}
+
+/// A neighbour (ARP or NDP) entry as the kernel reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Neighbour {
+    pub iface_idx: u32,
+    pub destination: IpAddr,
+    /// Hardware address. Proxy and incomplete entries have none.
+    pub address: Option<Vec<u8>>,
+    pub state: NeighbourState,
+    /// We answer for `destination` on behalf of another host.
+    pub proxy: bool,
+    /// The neighbour is an IPv6 router.
+    pub router: bool,
+}
+
+impl Neighbour {
+    /// Read a neighbour message. `None` for entries without an IP destination (bridge fdb).
+    pub fn from_message(message: &NeighbourMessage) -> Option<Neighbour> {
+        Some(Neighbour {
+            iface_idx: message.header.ifindex,
+            destination: ip_from_bytes(destination(message)?)?,
+            address: link_local_address(message).cloned(),
+            state: NeighbourState::from(message.header.state),
+            proxy: message.header.flags & NTF_PROXY != 0,
+            router: message.header.flags & NTF_ROUTER != 0,
+        })
+    }
+
+    fn same_entry(&self, other: &Neighbour) -> bool {
+        self.iface_idx == other.iface_idx
+            && self.destination == other.destination
+            && self.proxy == other.proxy
+    }
+}
+
+fn link_local_address(neighbour: &NeighbourMessage) -> Option<&Vec<u8>> {
+    neighbour.nlas.iter().find_map(|nla| match nla {
+        Nla::LinkLocalAddress(address) => Some(address),
+        _ => None,
+    })
+}
+
+fn destination(neighbour: &NeighbourMessage) -> Option<&Vec<u8>> {
+    neighbour.nlas.iter().find_map(|nla| match nla {
+        Nla::Destination(address) => Some(address),
+        _ => None,
+    })
+}
+
+async fn neighbour_messages(
+    handle: &Handle,
+    iface_idx: u32,
+    proxies: bool,
+) -> Result<Vec<NeighbourMessage>, RTNetlinkError> {
+    let mut request = handle.neighbours().get();
+    if proxies {
+        request = request.proxies();
+    }
+    let neighbours: Vec<NeighbourMessage> = request
+        .execute()
+        .try_collect()
+        .await
+        .map_err(RTNetlinkError::from)?;
+
+    Ok(neighbours
+        .into_iter()
+        .filter(|neighbour| neighbour.header.ifindex == iface_idx)
+        .collect())
+}
+
+async fn link_neighbours(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<Vec<NeighbourMessage>, RTNetlinkError> {
+    neighbour_messages(handle, iface_idx, false).await
+}
+
+/// Get the neighbour entries (both families) of an interface, proxy entries included.
+pub async fn get_neighbours(
+    handle: &Handle,
+    iface_idx: u32,
+) -> Result<Vec<Neighbour>, RTNetlinkError> {
+    let mut messages = neighbour_messages(handle, iface_idx, false).await?;
+    messages.extend(neighbour_messages(handle, iface_idx, true).await?);
+    Ok(messages
+        .iter()
+        .filter_map(Neighbour::from_message)
+        .collect())
+}
+
+async fn find_neighbour(
+    handle: &Handle,
+    wanted: &Neighbour,
+) -> Result<Option<Neighbour>, RTNetlinkError> {
+    let neighbours = get_neighbours(handle, wanted.iface_idx).await?;
+    Ok(neighbours
+        .into_iter()
+        .find(|found| found.same_entry(wanted)))
+}
+
+async fn set_neighbour(
+    handle: &Handle,
+    iface_idx: u32,
+    destination: IpAddr,
+    address: &[u8],
+    replace: bool,
+) -> Result<(), RTNetlinkError> {
+    let mut request = handle
+        .neighbours()
+        .add(iface_idx, destination)
+        .link_local_address(address)
+        .state(NUD_PERMANENT);
+    if replace {
+        request = request.replace();
+    }
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    let wanted = Neighbour {
+        iface_idx,
+        destination,
+        address: Some(address.to_vec()),
+        state: NeighbourState::Permanent,
+        proxy: false,
+        router: false,
+    };
+    match find_neighbour(handle, &wanted).await? {
+        Some(found) if found.address == wanted.address && found.state == wanted.state => Ok(()),
+        found => Err(RTNetlinkError::validation(
+            format!("{} lladdr {:?} permanent", destination, address),
+            format!("{:?}", found),
+        )),
+    }
+}
+
+/// Add a static (permanent) neighbour entry, e.g. to pin the gateway's hardware address.
+/// Fails if there is already an entry for `destination`.
+pub async fn add_neighbour(
+    handle: &Handle,
+    iface_idx: u32,
+    destination: IpAddr,
+    address: &[u8],
+) -> Result<(), RTNetlinkError> {
+    set_neighbour(handle, iface_idx, destination, address, false).await
+}
+
+/// Like `add_neighbour`, but overwrites an existing (learned or static) entry.
+pub async fn replace_neighbour(
+    handle: &Handle,
+    iface_idx: u32,
+    destination: IpAddr,
+    address: &[u8],
+) -> Result<(), RTNetlinkError> {
+    set_neighbour(handle, iface_idx, destination, address, true).await
+}
+
+/// Answer ARP/NDP requests for `destination` on the interface (`ip neigh add proxy`).
+pub async fn add_proxy_neighbour(
+    handle: &Handle,
+    iface_idx: u32,
+    destination: IpAddr,
+) -> Result<(), RTNetlinkError> {
+    let request = handle
+        .neighbours()
+        .add(iface_idx, destination)
+        .flags(NTF_PROXY);
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    let wanted = Neighbour {
+        iface_idx,
+        destination,
+        address: None,
+        state: NeighbourState::None,
+        proxy: true,
+        router: false,
+    };
+    match find_neighbour(handle, &wanted).await? {
+        Some(_) => Ok(()),
+        None => Err(RTNetlinkError::validation(
+            format!("proxy entry for {} on link {}", destination, iface_idx),
+            "none",
+        )),
+    }
+}
+
+/// Delete a neighbour (or proxy) entry.
+pub async fn del_neighbour(handle: &Handle, neighbour: &Neighbour) -> Result<(), RTNetlinkError> {
+    let messages = neighbour_messages(handle, neighbour.iface_idx, neighbour.proxy).await?;
+    let message = messages.into_iter().find(|message| {
+        Neighbour::from_message(message).is_some_and(|found| found.same_entry(neighbour))
+    });
+    if let Some(message) = message {
+        match handle.neighbours().del(message).execute().await {
+            Ok(()) => {}
+            // It can expire on its own in the meantime.
+            Err(e) => match RTNetlinkError::from(e) {
+                RTNetlinkError::Netlink(libc::ENOENT) => {}
+                e => return Err(e),
+            },
+        }
+    }
+
+    // Validate:
+    match find_neighbour(handle, neighbour).await? {
+        None => Ok(()),
+        Some(found) => Err(RTNetlinkError::validation(
+            format!(
+                "no neighbour {} on link {}",
+                neighbour.destination, neighbour.iface_idx
+            ),
+            format!("{:?}", found),
+        )),
+    }
+}
+
+/// Flush the learned neighbour (ARP/NDP) entries of an interface. Like `ip neigh flush`,
+/// permanent and NOARP entries are kept.
+pub async fn flush_neighbours(handle: &Handle, iface_idx: u32) -> Result<(), RTNetlinkError> {
+    let flushed: Vec<NeighbourMessage> = link_neighbours(handle, iface_idx)
+        .await?
+        .into_iter()
+        .filter(|neighbour| neighbour.header.state & (NUD_PERMANENT | NUD_NOARP) == 0)
+        .collect();
+
+    for neighbour in flushed.iter() {
+        match handle.neighbours().del(neighbour.clone()).execute().await {
+            Ok(()) => {}
+            // Entries can expire on their own while we're iterating.
+            Err(e) => match RTNetlinkError::from(e) {
+                RTNetlinkError::Netlink(libc::ENOENT) => {}
+                e => return Err(e),
+            },
+        }
+    }
+
+    // Validate. New entries may be learned in the meantime, but none of the flushed
+    // destinations may still map to the link-layer address they had.
+    let remaining = link_neighbours(handle, iface_idx).await?;
+    for old in flushed.iter() {
+        let stale = remaining.iter().find(|new| {
+            destination(new) == destination(old)
+                && link_local_address(new).is_some()
+                && link_local_address(new) == link_local_address(old)
+                && new.header.state == old.header.state
+        });
+        if let Some(stale) = stale {
+            return Err(RTNetlinkError::validation(
+                format!("no neighbour {:?} on link {}", destination(old), iface_idx),
+                format!("{:?}", stale.nlas),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_neighbour {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // What the kernel would send for `neighbour`.
+    fn message(neighbour: &Neighbour, state: u16) -> NeighbourMessage {
+        let mut message = NeighbourMessage::default();
+        message.header.family = match neighbour.destination {
+            IpAddr::V4(_) => libc::AF_INET as u8,
+            IpAddr::V6(_) => libc::AF_INET6 as u8,
+        };
+        message.header.ifindex = neighbour.iface_idx;
+        message.header.state = state;
+        if neighbour.proxy {
+            message.header.flags |= NTF_PROXY;
+        }
+        if neighbour.router {
+            message.header.flags |= NTF_ROUTER;
+        }
+        message
+            .nlas
+            .push(Nla::Destination(match neighbour.destination {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            }));
+        if let Some(address) = &neighbour.address {
+            message.nlas.push(Nla::LinkLocalAddress(address.clone()));
+        }
+        message
+    }
+
+    #[test]
+    fn neighbour_from_message() {
+        let gateway = Neighbour {
+            iface_idx: 2,
+            destination: Ipv4Addr::new(192, 168, 7, 1).into(),
+            address: Some(vec![2, 0, 0, 0, 0, 1]),
+            state: NeighbourState::Reachable,
+            proxy: false,
+            router: false,
+        };
+        let found = Neighbour::from_message(&message(&gateway, NUD_REACHABLE));
+        assert_eq!(found, Some(gateway));
+
+        let router = Neighbour {
+            iface_idx: 3,
+            destination: Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).into(),
+            address: Some(vec![2, 0, 0, 0, 0, 2]),
+            state: NeighbourState::Stale,
+            proxy: false,
+            router: true,
+        };
+        let found = Neighbour::from_message(&message(&router, NUD_STALE));
+        assert_eq!(found, Some(router));
+
+        let proxy = Neighbour {
+            iface_idx: 2,
+            destination: Ipv4Addr::new(192, 168, 7, 50).into(),
+            address: None,
+            state: NeighbourState::None,
+            proxy: true,
+            router: false,
+        };
+        let found = Neighbour::from_message(&message(&proxy, NUD_NONE));
+        assert_eq!(found, Some(proxy));
+
+        // Bridge fdb entries have a hardware address only.
+        let mut fdb = NeighbourMessage::default();
+        fdb.nlas.push(Nla::LinkLocalAddress(vec![2, 0, 0, 0, 0, 3]));
+        assert_eq!(Neighbour::from_message(&fdb), None);
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn manage_neighbours() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let idx = net.client_idx;
+        let gateway: IpAddr = Ipv4Addr::new(192, 168, 7, 1).into();
+        let find = |neighbours: Vec<Neighbour>, destination: IpAddr, proxy: bool| {
+            neighbours
+                .into_iter()
+                .find(|found| found.destination == destination && found.proxy == proxy)
+        };
+
+        add_neighbour(handle, idx, gateway, &[2, 0, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        let found = find(get_neighbours(handle, idx).await.unwrap(), gateway, false).unwrap();
+        assert_eq!(found.address, Some(vec![2, 0, 0, 0, 0, 1]));
+        assert_eq!(found.state, NeighbourState::Permanent);
+        // Adding doesn't overwrite, replacing does.
+        assert!(add_neighbour(handle, idx, gateway, &[2, 0, 0, 0, 0, 2])
+            .await
+            .is_err());
+        replace_neighbour(handle, idx, gateway, &[2, 0, 0, 0, 0, 2])
+            .await
+            .unwrap();
+        let found = find(get_neighbours(handle, idx).await.unwrap(), gateway, false).unwrap();
+        assert_eq!(found.address, Some(vec![2, 0, 0, 0, 0, 2]));
+
+        let proxied: IpAddr = Ipv4Addr::new(192, 168, 7, 50).into();
+        add_proxy_neighbour(handle, idx, proxied).await.unwrap();
+        let proxy = find(get_neighbours(handle, idx).await.unwrap(), proxied, true).unwrap();
+        assert_eq!(proxy.address, None);
+
+        // A learned entry, as if it had been resolved.
+        let learned: IpAddr = Ipv4Addr::new(192, 168, 7, 3).into();
+        let request = handle
+            .neighbours()
+            .add(idx, learned)
+            .link_local_address(&[2, 0, 0, 0, 0, 3])
+            .state(NUD_STALE);
+        request.execute().await.unwrap();
+
+        // Flushing drops only the learned entry.
+        flush_neighbours(handle, idx).await.unwrap();
+        let neighbours = get_neighbours(handle, idx).await.unwrap();
+        assert!(find(neighbours.clone(), learned, false).is_none());
+        assert!(find(neighbours.clone(), gateway, false).is_some());
+        assert!(find(neighbours, proxied, true).is_some());
+
+        del_neighbour(handle, &found).await.unwrap();
+        del_neighbour(handle, &proxy).await.unwrap();
+        let neighbours = get_neighbours(handle, idx).await.unwrap();
+        assert!(find(neighbours.clone(), gateway, false).is_none());
+        assert!(find(neighbours, proxied, true).is_none());
+    }
+}
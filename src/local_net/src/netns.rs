@@ -0,0 +1,128 @@
+// Network namespaces. A socket belongs to the namespace it was created in for its whole
+// life, so targeting a namespace only means creating the socket there. That is done on a
+// short-lived thread that joins the namespace, so the rest of the process stays where it is.
+use rtnetlink::{Handle, NetworkNamespace, NETNS_PATH, SELF_NS_PATH};
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use crate::{get_link_message, RTNetlinkError};
+
+/// A network namespace to run operations in, through a `ConnectionManager` for it.
+#[derive(Clone, Debug)]
+pub enum NetNs {
+    /// The namespace of the process.
+    Current,
+    /// A namespace created with `ip netns add` (or `add_netns`), under /run/netns.
+    Named(String),
+    /// Any namespace file, e.g. /proc/PID/ns/net.
+    Path(PathBuf),
+    /// An open namespace file, e.g. from `pidfd_open`. Shared, so clones keep it open.
+    Fd(Arc<OwnedFd>),
+}
+
+impl NetNs {
+    fn open(&self) -> io::Result<OwnedFd> {
+        let path = match self {
+            NetNs::Current => PathBuf::from(SELF_NS_PATH),
+            NetNs::Named(name) => Path::new(NETNS_PATH).join(name),
+            NetNs::Path(path) => path.clone(),
+            NetNs::Fd(fd) => return fd.try_clone(),
+        };
+        Ok(OwnedFd::from(File::open(path)?))
+    }
+
+    /// Identifies the namespace: the same for every `NetNs` that refers to it, whether by
+    /// name, path or file.
+    pub fn id(&self) -> Result<u64, RTNetlinkError> {
+        Ok(File::from(self.open()?).metadata()?.ino())
+    }
+}
+
+/// Run `f` on a thread inside `ns` and return its result. Sockets `f` creates stay in `ns`
+/// after it returns. Blocks until `f` is done, so long-running work belongs in
+/// `spawn_blocking`.
+pub fn in_netns<T, F>(ns: &NetNs, f: F) -> Result<T, RTNetlinkError>
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    // Joining a namespace takes CAP_SYS_ADMIN, even the one we are in.
+    if let NetNs::Current = ns {
+        return Ok(f());
+    }
+
+    let fd = ns.open()?;
+    // Async sockets register with the runtime when they are created.
+    let runtime = tokio::runtime::Handle::try_current().ok();
+
+    thread::scope(|scope| {
+        let thread = scope.spawn(move || {
+            let _guard = runtime.as_ref().map(|runtime| runtime.enter());
+            match unsafe { libc::setns(fd.as_raw_fd(), libc::CLONE_NEWNET) } {
+                -1 => Err(RTNetlinkError::from(io::Error::last_os_error())),
+                _ => Ok(f()),
+            }
+        });
+        // A panic in `f` is the caller's, as if `f` had run on its own thread.
+        thread
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    })
+}
+
+/// Create a named namespace (`ip netns add NAME`).
+pub async fn add_netns(name: &str) -> Result<(), RTNetlinkError> {
+    NetworkNamespace::add(name.to_string())
+        .await
+        .map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    match NetNs::Named(name.to_string()).open() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(RTNetlinkError::validation(format!("namespace {}", name), e)),
+    }
+}
+
+/// Delete a named namespace (`ip netns del NAME`). It lives on until the last process and
+/// socket in it are gone.
+pub async fn del_netns(name: &str) -> Result<(), RTNetlinkError> {
+    NetworkNamespace::del(name.to_string())
+        .await
+        .map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    match Path::new(NETNS_PATH).join(name).exists() {
+        false => Ok(()),
+        true => Err(RTNetlinkError::validation(
+            format!("no namespace {}", name),
+            "still present",
+        )),
+    }
+}
+
+/// Move a link from the namespace of `handle` into `ns`. It keeps its name unless that is
+/// taken there, but loses its addresses and routes.
+pub async fn set_link_netns(
+    handle: &Handle,
+    iface_idx: u32,
+    ns: &NetNs,
+) -> Result<(), RTNetlinkError> {
+    let fd = ns.open()?;
+    let request = handle.link().set(iface_idx).setns_by_fd(fd.as_raw_fd());
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    match get_link_message(handle, iface_idx).await {
+        Err(RTNetlinkError::InterfaceNotFound) => Ok(()),
+        Ok(_) => Err(RTNetlinkError::validation(
+            format!("link {} moved to {:?}", iface_idx, ns),
+            "still present",
+        )),
+        Err(e) => Err(e),
+    }
+}
@@ -1,22 +1,41 @@
-use futures::{Future, TryStreamExt};
-use log::{debug, error, info, trace, warn};
+use futures::{Future, StreamExt, TryStreamExt};
+use log::{debug, error, trace, warn};
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_REQUEST};
 use netlink_packet_route::route::Nla;
-use netlink_packet_route::RouteMessage;
+use netlink_packet_route::{
+    RouteFlags, RouteMessage, RtnlMessage, AF_INET, AF_INET6, RTN_BLACKHOLE, RTN_BROADCAST,
+    RTN_LOCAL, RTN_MULTICAST, RTN_PROHIBIT, RTN_THROW, RTN_UNICAST, RTN_UNREACHABLE, RTPROT_BOOT,
+    RT_SCOPE_HOST, RT_SCOPE_LINK, RT_SCOPE_NOWHERE, RT_SCOPE_SITE, RT_SCOPE_UNIVERSE,
+    RT_TABLE_DEFAULT, RT_TABLE_LOCAL, RT_TABLE_MAIN, RT_TABLE_UNSPEC,
+};
 use rtnetlink::{Handle, IpVersion};
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
+use crate::address::ip_from_bytes;
+use crate::utils::run_all;
 use crate::RTNetlinkError;
 
-/// Get all routes from the kernel.
-pub async fn get_routes(handle: &Handle) -> Result<Vec<RouteMessage>, RTNetlinkError> {
-    let mut routes = handle.route().get(IpVersion::V4).execute();
-    let mut route_message_vec = Vec::new();
+async fn route_messages(
+    handle: &Handle,
+    ip_version: IpVersion,
+) -> Result<Vec<RouteMessage>, RTNetlinkError> {
+    handle
+        .route()
+        .get(ip_version)
+        .execute()
+        .try_collect()
+        .await
+        .map_err(RTNetlinkError::from)
+}
 
-    while let Some(route_message) = routes.try_next().await.map_err(RTNetlinkError::RTNetlink)? {
-        route_message_vec.push(route_message);
+/// Get all routes (both families, all tables) from the kernel.
+pub async fn get_routes(handle: &Handle) -> Result<Vec<Route>, RTNetlinkError> {
+    let mut routes = Vec::new();
+    for ip_version in [IpVersion::V4, IpVersion::V6] {
+        let messages = route_messages(handle, ip_version).await?;
+        routes.extend(messages.iter().filter_map(Route::from_message));
     }
-
-    Ok(route_message_vec)
+    Ok(routes)
 }
 
 /// Set the default route for an interface.
@@ -24,7 +43,7 @@ pub async fn set_default_route(
     handle: &Handle,
     iface_idx: u32,
     gateway: Ipv4Addr,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), RTNetlinkError> {
     let request = handle
         // Base route request:
         .route()
@@ -37,7 +56,7 @@ pub async fn set_default_route(
         // Kernel address:
         .destination_prefix(Ipv4Addr::new(0, 0, 0, 0), 0);
 
-    request.execute().await?;
+    request.execute().await.map_err(RTNetlinkError::from)?;
 
     Ok(())
 }
@@ -53,209 +72,850 @@ where
 {
     let mut routes = handle.route().get(ip_version).execute();
 
-    while let Some(route_message) = routes.try_next().await.map_err(RTNetlinkError::RTNetlink)? {
+    while let Some(route_message) = routes.try_next().await.map_err(RTNetlinkError::from)? {
         function(route_message.clone()).await?;
     }
 
     Ok(())
 }
 
-/// Flush all routes from an interface.
+/// Flush the routes of one family that go out through an interface. Routes in the local
+/// table belong to the interface's addresses and are left alone.
 pub async fn flush_routes(
     handle: &Handle,
     ip_version: IpVersion,
     iface_idx: u32,
 ) -> Result<(), RTNetlinkError> {
-    modify_routes(
-        handle,
-        ip_version.clone(),
-        async move |route_message| -> Result<(), RTNetlinkError> {
-            for nla in route_message.nlas.iter() {
-                if let Nla::Oif(oif) = nla {
-                    if *oif == iface_idx {
-                        let request = handle.route().del(route_message.clone());
-                        request.execute().await.map_err(RTNetlinkError::RTNetlink)?;
-                    }
-                }
-            }
-            Ok(())
-        },
-    )
+    let on_link = |route: &Route| route.iface_idx == Some(iface_idx) && route.table != Table::Local;
+
+    let messages = route_messages(handle, ip_version.clone()).await?;
+    let messages = messages
+        .into_iter()
+        .filter(|message| Route::from_message(message).is_some_and(|route| on_link(&route)));
+    run_all(messages, |message| async move {
+        let request = handle.route().del(message);
+        match request.execute().await.map_err(RTNetlinkError::from) {
+            Ok(()) => Ok(()),
+            // Already gone, e.g. with the route it depended on.
+            Err(e) if e.errno() == Some(libc::ESRCH) => Ok(()),
+            Err(e) => Err(e),
+        }
+    })
     .await?;
 
-    let mut routes = handle.route().get(ip_version).execute();
+    // Verify and return:
+    let messages = route_messages(handle, ip_version).await?;
+    let remaining = messages
+        .iter()
+        .filter_map(Route::from_message)
+        .filter(|route| on_link(route))
+        .count();
+    match remaining {
+        0 => Ok(()),
+        remaining => Err(RTNetlinkError::validation(
+            format!("no routes on link {}", iface_idx),
+            format!("{} routes", remaining),
+        )),
+    }
+}
+
+/// One of the paths of a multipath route.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NextHop {
+    pub iface_idx: u32,
+    pub gateway: Option<IpAddr>,
+    /// Share of the traffic relative to the other nexthops, 1..=256.
+    pub weight: u16,
+}
+
+/// A route as the kernel reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// Output interface. `None` for multipath routes, which have `nexthops` instead.
+    pub iface_idx: Option<u32>,
+    pub table: Table,
+    /// Who installed the route (RTPROT_*).
+    pub protocol: u8,
+    pub scope: Scope,
+    pub kind: RouteType,
+    /// Also known as priority. Lower is preferred.
+    pub metric: Option<u32>,
+    /// Preferred source address for traffic using this route.
+    pub source: Option<IpAddr>,
+    pub mtu: Option<u32>,
+    pub nexthops: Vec<NextHop>,
+}
 
-    while let Some(route_message) = routes.try_next().await.map_err(RTNetlinkError::RTNetlink)? {
-        for nla in route_message.nlas.iter() {
-            if let Nla::Oif(oif) = nla {
-                if *oif == iface_idx {
-                    let request = handle.route().del(route_message.clone());
-                    request.execute().await.map_err(RTNetlinkError::RTNetlink)?;
-                }
+impl Route {
+    /// A route to DESTINATION/PREFIX_LEN through `iface_idx`, directly connected unless a
+    /// gateway is given.
+    pub fn new(
+        iface_idx: u32,
+        destination: IpAddr,
+        prefix_len: u8,
+        gateway: Option<IpAddr>,
+    ) -> Route {
+        Route {
+            destination,
+            prefix_len,
+            gateway,
+            iface_idx: Some(iface_idx),
+            table: Table::Main,
+            protocol: RTPROT_BOOT,
+            scope: match gateway {
+                Some(_) => Scope::Universe,
+                None => Scope::Link,
+            },
+            kind: RouteType::Unicast,
+            metric: None,
+            source: None,
+            mtu: None,
+            nexthops: Vec::new(),
+        }
+    }
+
+    /// A blackhole, unreachable, prohibit or throw route for DESTINATION/PREFIX_LEN. These
+    /// have no output interface.
+    pub fn reject(kind: RouteType, destination: IpAddr, prefix_len: u8) -> Route {
+        Route {
+            iface_idx: None,
+            scope: Scope::Universe,
+            kind,
+            ..Route::new(0, destination, prefix_len, None)
+        }
+    }
+
+    /// Read a route message. `None` for families other than IPv4 and IPv6.
+    pub fn from_message(message: &RouteMessage) -> Option<Route> {
+        let family = message.header.address_family;
+        let unspecified = match family as u16 {
+            AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            _ => return None,
+        };
+
+        let mut route = Route {
+            // The default route has no destination.
+            destination: unspecified,
+            prefix_len: message.header.destination_prefix_length,
+            gateway: None,
+            iface_idx: None,
+            table: Table::from(message.header.table as u32),
+            protocol: message.header.protocol,
+            scope: Scope::from(message.header.scope),
+            kind: RouteType::from(message.header.kind),
+            metric: None,
+            source: None,
+            mtu: None,
+            nexthops: Vec::new(),
+        };
+        for nla in &message.nlas {
+            match nla {
+                Nla::Destination(bytes) => route.destination = ip_from_bytes(bytes)?,
+                Nla::Gateway(bytes) => route.gateway = ip_from_bytes(bytes),
+                Nla::Oif(iface_idx) => route.iface_idx = Some(*iface_idx),
+                // Tables above 255 only fit here.
+                Nla::Table(table) => route.table = Table::from(*table),
+                Nla::Priority(metric) => route.metric = Some(*metric),
+                Nla::PrefSource(bytes) => route.source = ip_from_bytes(bytes),
+                Nla::Metrics(bytes) => route.mtu = parse_mtu(bytes),
+                Nla::MultiPath(bytes) => route.nexthops = parse_nexthops(bytes),
+                _ => {}
             }
         }
+        Some(route)
     }
 
-    // Verify and return:
-    match get_routes(handle).await?.len() {
-        0 => Ok(()),
-        _ => Err(RTNetlinkError::ValidationFailed),
+    fn ip_version_name(&self) -> &'static str {
+        match self.destination {
+            IpAddr::V4(_) => "IPv4",
+            IpAddr::V6(_) => "IPv6",
+        }
+    }
+
+    pub fn ip_version(&self) -> IpVersion {
+        match self.destination {
+            IpAddr::V4(_) => IpVersion::V4,
+            IpAddr::V6(_) => IpVersion::V6,
+        }
+    }
+
+    // Whether this route (read back from the kernel) is `wanted`. The kernel fills in a metric
+    // when none was given, so only compare it when `wanted` has one.
+    // Reject routes are matched on their type instead: IPv4 ones have no interface and IPv6
+    // ones report loopback.
+    pub(crate) fn same_destination(&self, wanted: &Route) -> bool {
+        let same_target = match wanted.kind.is_reject() {
+            true => self.kind == wanted.kind,
+            false => self.iface_idx == wanted.iface_idx,
+        };
+        self.destination == wanted.destination
+            && self.prefix_len == wanted.prefix_len
+            && same_target
+            && self.table == wanted.table
+            && (wanted.metric.is_none() || self.metric == wanted.metric)
     }
 }
 
-pub struct Ipv4Route {
-    pub destination: Ipv4Addr,
-    pub gateway: Ipv4Addr,
+// RTA_MULTIPATH is a list of struct rtnexthop, each followed by its own attributes.
+const RTNH_LEN: usize = 8;
+const RTA_GATEWAY: u16 = 5;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
 }
 
-pub struct Ipv6Route {
-    pub destination: Ipv6Addr,
-    pub gateway: Ipv6Addr,
+fn parse_nexthops(mut bytes: &[u8]) -> Vec<NextHop> {
+    let mut nexthops = Vec::new();
+    while bytes.len() >= RTNH_LEN {
+        let len = u16::from_ne_bytes([bytes[0], bytes[1]]) as usize;
+        if len < RTNH_LEN || len > bytes.len() {
+            break;
+        }
+        let mut nexthop = NextHop {
+            iface_idx: u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            gateway: None,
+            // rtnh_hops is the weight minus one.
+            weight: bytes[3] as u16 + 1,
+        };
+
+        let mut attributes = &bytes[RTNH_LEN..len];
+        while attributes.len() >= 4 {
+            let attr_len = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+            let attr_type = u16::from_ne_bytes([attributes[2], attributes[3]]);
+            if attr_len < 4 || attr_len > attributes.len() {
+                break;
+            }
+            if attr_type == RTA_GATEWAY {
+                nexthop.gateway = ip_from_bytes(&attributes[4..attr_len]);
+            }
+            attributes = &attributes[align(attr_len).min(attributes.len())..];
+        }
+
+        nexthops.push(nexthop);
+        bytes = &bytes[align(len).min(bytes.len())..];
+    }
+    nexthops
 }
 
-pub enum VersionOptions {
-    V4(Ipv4Route),
-    V6(Ipv6Route),
+/// How far a route (or address) is valid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Universe,
+    Site,
+    /// Directly connected, no gateway needed.
+    Link,
+    /// Only this host.
+    Host,
+    Nowhere,
+    Other(u8),
 }
 
-pub struct Route {
-    pub iface_idx: u32,
-    pub prefix_len: u8,
-    pub version_opts: VersionOptions,
+impl From<u8> for Scope {
+    fn from(scope: u8) -> Self {
+        match scope {
+            RT_SCOPE_UNIVERSE => Scope::Universe,
+            RT_SCOPE_SITE => Scope::Site,
+            RT_SCOPE_LINK => Scope::Link,
+            RT_SCOPE_HOST => Scope::Host,
+            RT_SCOPE_NOWHERE => Scope::Nowhere,
+            other => Scope::Other(other),
+        }
+    }
+}
+
+impl From<Scope> for u8 {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::Universe => RT_SCOPE_UNIVERSE,
+            Scope::Site => RT_SCOPE_SITE,
+            Scope::Link => RT_SCOPE_LINK,
+            Scope::Host => RT_SCOPE_HOST,
+            Scope::Nowhere => RT_SCOPE_NOWHERE,
+            Scope::Other(other) => other,
+        }
+    }
+}
+
+/// A routing table. `Id` is any table that isn't one of the reserved ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Table {
+    Unspec,
+    Default,
+    Main,
+    Local,
+    Id(u32),
 }
 
-pub struct Scope {}
-impl Scope {
-    pub const UNIVERSE: u8 = 0;
-    pub const SITE: u8 = 200;
-    pub const LINK: u8 = 253;
-    pub const HOST: u8 = 254;
-    pub const NOWHERE: u8 = 255;
+impl From<u32> for Table {
+    fn from(table: u32) -> Self {
+        match table {
+            0 => Table::Unspec,
+            253 => Table::Default,
+            254 => Table::Main,
+            255 => Table::Local,
+            other => Table::Id(other),
+        }
+    }
+}
+
+impl From<Table> for u32 {
+    fn from(table: Table) -> Self {
+        match table {
+            Table::Unspec => RT_TABLE_UNSPEC as u32,
+            Table::Default => RT_TABLE_DEFAULT as u32,
+            Table::Main => RT_TABLE_MAIN as u32,
+            Table::Local => RT_TABLE_LOCAL as u32,
+            Table::Id(other) => other,
+        }
+    }
+}
+
+/// What happens to packets matching a route.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteType {
+    /// Forwarded to the gateway or destination.
+    Unicast,
+    /// Delivered locally.
+    Local,
+    Broadcast,
+    Multicast,
+    /// Silently dropped.
+    Blackhole,
+    /// Dropped with ICMP host unreachable.
+    Unreachable,
+    /// Dropped with ICMP administratively prohibited.
+    Prohibit,
+    /// Lookup continues in the next table (policy routing).
+    Throw,
+    Other(u8),
+}
+
+impl RouteType {
+    /// Whether traffic matching the route is dropped or looked up elsewhere instead of going
+    /// out through an interface.
+    pub fn is_reject(&self) -> bool {
+        matches!(
+            self,
+            RouteType::Blackhole | RouteType::Unreachable | RouteType::Prohibit | RouteType::Throw
+        )
+    }
+}
+
+impl From<u8> for RouteType {
+    fn from(kind: u8) -> Self {
+        match kind {
+            RTN_UNICAST => RouteType::Unicast,
+            RTN_LOCAL => RouteType::Local,
+            RTN_BROADCAST => RouteType::Broadcast,
+            RTN_MULTICAST => RouteType::Multicast,
+            RTN_BLACKHOLE => RouteType::Blackhole,
+            RTN_UNREACHABLE => RouteType::Unreachable,
+            RTN_PROHIBIT => RouteType::Prohibit,
+            RTN_THROW => RouteType::Throw,
+            other => RouteType::Other(other),
+        }
+    }
+}
+
+impl From<RouteType> for u8 {
+    fn from(kind: RouteType) -> Self {
+        match kind {
+            RouteType::Unicast => RTN_UNICAST,
+            RouteType::Local => RTN_LOCAL,
+            RouteType::Broadcast => RTN_BROADCAST,
+            RouteType::Multicast => RTN_MULTICAST,
+            RouteType::Blackhole => RTN_BLACKHOLE,
+            RouteType::Unreachable => RTN_UNREACHABLE,
+            RouteType::Prohibit => RTN_PROHIBIT,
+            RouteType::Throw => RTN_THROW,
+            RouteType::Other(other) => other,
+        }
+    }
+}
+
+// RTA_METRICS is a nested list of RTAX_* attributes, all u32.
+const RTAX_MTU: u16 = 2;
+
+fn parse_mtu(mut bytes: &[u8]) -> Option<u32> {
+    while bytes.len() >= 8 {
+        let len = u16::from_ne_bytes([bytes[0], bytes[1]]) as usize;
+        let kind = u16::from_ne_bytes([bytes[2], bytes[3]]);
+        if len < 4 || len > bytes.len() {
+            break;
+        }
+        if kind == RTAX_MTU && len >= 8 {
+            return Some(u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]));
+        }
+        bytes = &bytes[((len + 3) & !3).min(bytes.len())..];
+    }
+    None
+}
+
+fn mtu_metrics(mtu: u32) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(8);
+    bytes.extend_from_slice(&8u16.to_ne_bytes());
+    bytes.extend_from_slice(&RTAX_MTU.to_ne_bytes());
+    bytes.extend_from_slice(&mtu.to_ne_bytes());
+    bytes
+}
+
+fn encode_nexthops(route: &Route) -> Result<Vec<u8>, RTNetlinkError> {
+    let mut bytes = Vec::new();
+    for nexthop in &route.nexthops {
+        if !(1..=256).contains(&nexthop.weight) {
+            return Err(RTNetlinkError::invalid(format!(
+                "nexthop weight {} outside 1..=256",
+                nexthop.weight
+            )));
+        }
+        let gateway = match (route.destination, nexthop.gateway) {
+            (_, None) => Vec::new(),
+            (IpAddr::V4(_), Some(IpAddr::V4(gateway))) => gateway.octets().to_vec(),
+            (IpAddr::V6(_), Some(IpAddr::V6(gateway))) => gateway.octets().to_vec(),
+            (_, Some(gateway)) => {
+                return Err(RTNetlinkError::invalid(format!(
+                    "nexthop gateway {} on an {} route",
+                    gateway,
+                    route.ip_version_name()
+                )))
+            }
+        };
+
+        let attribute_len = match gateway.is_empty() {
+            true => 0,
+            false => 4 + gateway.len(),
+        };
+        let len = RTNH_LEN + align(attribute_len);
+        bytes.extend_from_slice(&(len as u16).to_ne_bytes());
+        bytes.push(0); // flags
+        bytes.push((nexthop.weight - 1) as u8);
+        bytes.extend_from_slice(&nexthop.iface_idx.to_ne_bytes());
+        if attribute_len > 0 {
+            bytes.extend_from_slice(&(attribute_len as u16).to_ne_bytes());
+            bytes.extend_from_slice(&RTA_GATEWAY.to_ne_bytes());
+            bytes.extend_from_slice(&gateway);
+            bytes.resize(bytes.len() + align(attribute_len) - attribute_len, 0);
+        }
+    }
+    Ok(bytes)
 }
 
-pub struct Table {}
-impl Table {
-    pub const UNSPEC: u32 = 0;
-    pub const DEFAULT: u32 = 253;
-    pub const LOCAL: u32 = 255;
-    pub const MAIN: u32 = 254;
+// The attributes the builders don't cover, the same for both families.
+fn extra_nlas(route: &Route) -> Result<Vec<Nla>, RTNetlinkError> {
+    let mut nlas = Vec::new();
+    if !route.nexthops.is_empty() {
+        nlas.push(Nla::MultiPath(encode_nexthops(route)?));
+    }
+    if let Some(metric) = route.metric {
+        nlas.push(Nla::Priority(metric));
+    }
+    if let Some(mtu) = route.mtu {
+        nlas.push(Nla::Metrics(mtu_metrics(mtu)));
+    }
+    Ok(nlas)
 }
 
 pub async fn add_route(handle: &Handle, route: Route) -> Result<(), RTNetlinkError> {
-    // Although the function calls are the same, the type changes after .v4 or .v6.
+    install_route(handle, route, false).await
+}
+
+/// Add a route, replacing the one with the same destination, table and metric if there is one.
+pub async fn replace_route(handle: &Handle, route: Route) -> Result<(), RTNetlinkError> {
+    install_route(handle, route, true).await
+}
+
+async fn install_route(handle: &Handle, route: Route, replace: bool) -> Result<(), RTNetlinkError> {
+    send_route(handle, &route, replace).await?;
+
+    // Verify
+    let messages = route_messages(handle, route.ip_version()).await?;
+    match missing_route(&messages, std::slice::from_ref(&route)) {
+        None => {
+            debug!("Route added successfully!");
+            Ok(())
+        }
+        Some(error) => {
+            warn!("Route was not found after adding it.");
+            Err(error)
+        }
+    }
+}
+
+/// Add several routes and check them all with one dump per family at the end.
+pub async fn add_routes(handle: &Handle, routes: &[Route]) -> Result<(), RTNetlinkError> {
+    run_all(routes, |route| send_route(handle, route, false)).await?;
+
+    // Verify
+    for ip_version in [IpVersion::V4, IpVersion::V6] {
+        let routes: Vec<Route> = routes
+            .iter()
+            .filter(|route| route.ip_version() == ip_version)
+            .cloned()
+            .collect();
+        if routes.is_empty() {
+            continue;
+        }
+        let messages = route_messages(handle, ip_version).await?;
+        if let Some(error) = missing_route(&messages, &routes) {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// The error for the first of `routes` that is not among `messages`.
+fn missing_route(messages: &[RouteMessage], routes: &[Route]) -> Option<RTNetlinkError> {
+    let found: Vec<Route> = messages.iter().filter_map(Route::from_message).collect();
+    let route = routes
+        .iter()
+        .find(|route| !found.iter().any(|found| found.same_destination(route)))?;
+    Some(RTNetlinkError::validation(
+        format!(
+            "{}/{} via link {:?}",
+            route.destination, route.prefix_len, route.iface_idx
+        ),
+        "no such route",
+    ))
+}
+
+async fn send_route(handle: &Handle, route: &Route, replace: bool) -> Result<(), RTNetlinkError> {
+    if route.iface_idx.is_none() && route.nexthops.is_empty() && !route.kind.is_reject() {
+        return Err(RTNetlinkError::invalid(format!(
+            "{:?} route to {}/{} without an output interface or nexthops",
+            route.kind, route.destination, route.prefix_len
+        )));
+    }
+    debug!(
+        "Adding {}/{} via {:?} on interface {:?} (nexthops {:?})",
+        route.destination, route.prefix_len, route.gateway, route.iface_idx, route.nexthops
+    );
+
+    let mut request = handle.route().add();
+    if let Some(iface_idx) = route.iface_idx.filter(|_| !route.kind.is_reject()) {
+        request = request.output_interface(iface_idx);
+    }
+    if replace {
+        request = request.replace();
+    }
+    let request = request
+        .table_id(route.table.into())
+        .protocol(route.protocol)
+        .scope(route.scope.into())
+        .kind(route.kind.into());
 
-    match &route.version_opts {
-        VersionOptions::V4(v4_opts) => {
-            debug!(
-                "Adding {}/{} via {} on interface {}",
-                v4_opts.destination, route.prefix_len, v4_opts.gateway, route.iface_idx
-            );
-            handle
-                .route()
-                .add()
+    // Although the function calls are the same, the type changes after .v4 or .v6.
+    let wrong_family = |what: &str, found: IpAddr| {
+        RTNetlinkError::invalid(format!(
+            "{} {} on an {} route",
+            what,
+            found,
+            route.ip_version_name()
+        ))
+    };
+    let res = match route.destination {
+        IpAddr::V4(destination) => {
+            let mut request = request
                 .v4()
-                // Gateway and interface:
-                .output_interface(route.iface_idx)
-                .gateway(v4_opts.gateway)
-                // Kernel address:
-                .destination_prefix(v4_opts.destination, route.prefix_len)
-                .table_id(243)
-                .scope(Scope::UNIVERSE)
-                .protocol(4)
-                .execute()
-                .await
-                .map_err(|e| {
-                    error!("add_route: RTNETLINK answers with error");
-                    RTNetlinkError::RTNetlink(e)
-                })?
+                .destination_prefix(destination, route.prefix_len);
+            match route.gateway {
+                Some(IpAddr::V4(gateway)) => request = request.gateway(gateway),
+                Some(gateway) => return Err(wrong_family("gateway", gateway)),
+                None => {}
+            }
+            match route.source {
+                Some(IpAddr::V4(source)) => request = request.pref_source(source),
+                Some(source) => return Err(wrong_family("source", source)),
+                None => {}
+            }
+            request.message_mut().nlas.extend(extra_nlas(route)?);
+            request.execute().await
         }
-        VersionOptions::V6(v6_opts) => {
-            debug!(
-                "Adding {}/{} via {} on interface {}",
-                v6_opts.destination, route.prefix_len, v6_opts.gateway, route.iface_idx
-            );
-            handle
-                .route()
-                .add()
+        IpAddr::V6(destination) => {
+            let mut request = request
                 .v6()
-                // Gateway and interface:
-                .output_interface(route.iface_idx)
-                .gateway(v6_opts.gateway)
-                // Kernel address:
-                .destination_prefix(v6_opts.destination, route.prefix_len)
-                .execute()
-                .await
-                .map_err(|e| {
-                    error!("add_route: RTNETLINK answers with error");
-                    RTNetlinkError::RTNetlink(e)
-                })?
+                .destination_prefix(destination, route.prefix_len);
+            match route.gateway {
+                Some(IpAddr::V6(gateway)) => request = request.gateway(gateway),
+                Some(gateway) => return Err(wrong_family("gateway", gateway)),
+                None => {}
+            }
+            match route.source {
+                Some(IpAddr::V6(source)) => request = request.pref_source(source),
+                Some(source) => return Err(wrong_family("source", source)),
+                None => {}
+            }
+            request.message_mut().nlas.extend(extra_nlas(route)?);
+            request.execute().await
         }
     };
+    res.map_err(|e| {
+        error!("add_route: RTNETLINK answers with error");
+        RTNetlinkError::from(e)
+    })?;
     trace!("add route executed successfully");
+    Ok(())
+}
+
+/// Delete a route. Matched on destination, prefix, output interface, table and (if set) metric.
+pub async fn del_route(handle: &Handle, route: &Route) -> Result<(), RTNetlinkError> {
+    for message in route_messages(handle, route.ip_version()).await? {
+        if Route::from_message(&message).is_some_and(|found| found.same_destination(route)) {
+            let request = handle.route().del(message);
+            request.execute().await.map_err(RTNetlinkError::from)?;
+        }
+    }
 
     // Verify
-    let ip_version = match route.version_opts {
-        VersionOptions::V4(_) => IpVersion::V4,
-        VersionOptions::V6(_) => IpVersion::V6,
-    };
+    let messages = route_messages(handle, route.ip_version()).await?;
+    match messages
+        .iter()
+        .filter_map(Route::from_message)
+        .any(|found| found.same_destination(route))
+    {
+        true => Err(RTNetlinkError::validation(
+            format!("no route to {}/{}", route.destination, route.prefix_len),
+            "route still present",
+        )),
+        false => Ok(()),
+    }
+}
 
-    let mut res_routes = handle.route().get(ip_version).execute();
+/// What to ask the kernel a route for, like `ip route get`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteQuery {
+    pub destination: IpAddr,
+    /// Pretend the traffic comes from this address.
+    pub source: Option<IpAddr>,
+    /// Firewall mark, for rules that match on it.
+    pub mark: Option<u32>,
+    /// Pretend the traffic arrived on this interface (a forwarding lookup).
+    pub iif: Option<u32>,
+}
 
-    while let Some(route_message) = res_routes
-        .try_next()
-        .await
-        .map_err(RTNetlinkError::RTNetlink)?
-    {
-        for nla in route_message.nlas.iter() {
-            if let Nla::Oif(oif) = nla {
-                if *oif == route.iface_idx {
-                    debug!("Route added successfully!");
-                    return Ok(());
-                }
-            }
+impl RouteQuery {
+    pub fn new(destination: IpAddr) -> RouteQuery {
+        RouteQuery {
+            destination,
+            source: None,
+            mark: None,
+            iif: None,
+        }
+    }
+}
+
+fn host_prefix(address: IpAddr) -> (u8, Vec<u8>) {
+    match address {
+        IpAddr::V4(address) => (32, address.octets().to_vec()),
+        IpAddr::V6(address) => (128, address.octets().to_vec()),
+    }
+}
+
+/// Ask the kernel which route it would pick for `query`. The result carries the output
+/// interface, gateway and preferred source it would use.
+pub async fn lookup_route(handle: &Handle, query: &RouteQuery) -> Result<Route, RTNetlinkError> {
+    let mut message = RouteMessage::default();
+    let (prefix_len, destination) = host_prefix(query.destination);
+    message.header.address_family = match query.destination {
+        IpAddr::V4(_) => AF_INET as u8,
+        IpAddr::V6(_) => AF_INET6 as u8,
+    };
+    // Report the table the route came from instead of always main.
+    message.header.flags = RouteFlags::RTM_F_LOOKUP_TABLE;
+    message.header.destination_prefix_length = prefix_len;
+    message.nlas.push(Nla::Destination(destination));
+    if let Some(source) = query.source {
+        if source.is_ipv4() != query.destination.is_ipv4() {
+            return Err(RTNetlinkError::invalid(format!(
+                "source {} for destination {} of another family",
+                source, query.destination
+            )));
         }
+        let (prefix_len, source) = host_prefix(source);
+        message.header.source_prefix_length = prefix_len;
+        message.nlas.push(Nla::Source(source));
+    }
+    if let Some(mark) = query.mark {
+        message.nlas.push(Nla::Mark(mark));
+    }
+    if let Some(iif) = query.iif {
+        message.nlas.push(Nla::Iif(iif));
     }
 
-    warn!("Route was not found after adding it.");
-    Err(RTNetlinkError::ValidationFailed)
+    // Not a dump: the kernel answers with exactly one route (or an error).
+    let mut request = NetlinkMessage::from(RtnlMessage::GetRoute(message));
+    request.header.flags = NLM_F_REQUEST;
+    let mut response = handle
+        .clone()
+        .request(request)
+        .map_err(RTNetlinkError::from)?;
+
+    while let Some(message) = response.next().await {
+        match message.payload {
+            NetlinkPayload::InnerMessage(RtnlMessage::NewRoute(route)) => {
+                return Route::from_message(&route).ok_or_else(|| {
+                    let message = NetlinkMessage::from(RtnlMessage::NewRoute(route));
+                    RTNetlinkError::from(rtnetlink::Error::UnexpectedMessage(message))
+                })
+            }
+            NetlinkPayload::Error(e) => {
+                return Err(RTNetlinkError::from(rtnetlink::Error::NetlinkError(e)))
+            }
+            _ => {}
+        }
+    }
+    // The connection went away before the kernel answered.
+    Err(RTNetlinkError::from(rtnetlink::Error::RequestFailed))
 }
 
 #[cfg(test)]
 mod test_routes {
-    use default_net::get_interfaces;
-
+    #[cfg(feature = "testbed")]
     #[tokio::test]
     async fn add_route() {
-        pretty_env_logger::init();
         use super::*;
+        use crate::{add_address, Address, TestNet};
         use std::net::Ipv4Addr;
 
-        let iface_idx = get_interfaces()
-            .iter()
-            .find(|iface| iface.name.starts_with('e'))
-            .unwrap()
-            .index;
-        println!("Using interface index: {}", iface_idx);
-
-        let (connection, handle, _) = rtnetlink::new_connection().unwrap();
-        tokio::spawn(connection);
+        let net = TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let address = Address::new(net.client_idx, Ipv4Addr::new(192, 168, 0, 2).into(), 24);
+        add_address(handle, &address).await.unwrap();
 
-        let destination = Ipv4Addr::new(192, 168, 0, 0);
+        let destination = Ipv4Addr::new(10, 0, 0, 0);
         let prefix_len = 24;
         let gateway = Ipv4Addr::new(192, 168, 0, 1);
+        let route = Route::new(
+            net.client_idx,
+            destination.into(),
+            prefix_len,
+            Some(gateway.into()),
+        );
+
+        add_route(handle, route.clone()).await.unwrap();
+        del_route(handle, &route).await.unwrap();
+    }
 
-        add_route(
-            &handle,
-            Route {
-                iface_idx,
-                prefix_len,
-                version_opts: VersionOptions::V4(Ipv4Route {
-                    destination,
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn add_routes_in_order() {
+        use super::*;
+        use crate::TestNet;
+
+        let net = TestNet::new().await.unwrap();
+        // The gateway is only reachable through the route before it.
+        let subnet = Route::new(
+            net.client_idx,
+            Ipv4Addr::new(172, 16, 0, 0).into(),
+            24,
+            None,
+        );
+        let gateway = Some(Ipv4Addr::new(172, 16, 0, 1).into());
+        let routes: Vec<Route> = (0..100u8)
+            .map(|i| {
+                Route::new(
+                    net.client_idx,
+                    Ipv4Addr::new(10, i, 0, 0).into(),
+                    16,
                     gateway,
-                }),
+                )
+            })
+            .collect();
+        let all = [vec![subnet], routes].concat();
+
+        add_routes(&net.client_handle, &all).await.unwrap();
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn add_reject_routes() {
+        use super::*;
+        use crate::TestNet;
+
+        let net = TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let routes = [
+            Route::reject(RouteType::Blackhole, Ipv4Addr::new(10, 1, 0, 0).into(), 16),
+            Route::reject(
+                RouteType::Unreachable,
+                Ipv4Addr::new(10, 2, 0, 0).into(),
+                16,
+            ),
+            Route::reject(RouteType::Prohibit, Ipv4Addr::new(10, 3, 0, 0).into(), 16),
+            Route::reject(RouteType::Unreachable, "fd00:1::".parse().unwrap(), 64),
+        ];
+
+        for route in &routes {
+            add_route(handle, route.clone()).await.unwrap();
+        }
+        let installed = get_routes(handle).await.unwrap();
+        for route in &routes {
+            let found = installed
+                .iter()
+                .find(|found| found.destination == route.destination)
+                .unwrap();
+            assert_eq!(found.kind, route.kind);
+            assert_eq!(found.table, Table::Main);
+        }
+        for route in &routes {
+            del_route(handle, route).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn route_attributes_round_trip() {
+        use super::*;
+
+        let mut route = Route::new(
+            2,
+            Ipv4Addr::new(10, 1, 0, 0).into(),
+            16,
+            Some(Ipv4Addr::new(10, 0, 0, 1).into()),
+        );
+        route.metric = Some(100);
+        route.mtu = Some(1400);
+
+        let mut message = RouteMessage::default();
+        message.header.address_family = AF_INET as u8;
+        message.header.destination_prefix_length = 16;
+        message.header.table = RT_TABLE_MAIN;
+        message.header.protocol = RTPROT_BOOT;
+        message.header.kind = RTN_UNICAST;
+        message.nlas = vec![
+            Nla::Destination(vec![10, 1, 0, 0]),
+            Nla::Gateway(vec![10, 0, 0, 1]),
+            Nla::Oif(2),
+        ];
+        message.nlas.extend(extra_nlas(&route).unwrap());
+
+        assert_eq!(Route::from_message(&message), Some(route));
+        assert_eq!(Table::from(u32::from(Table::Id(1000))), Table::Id(1000));
+
+        let nexthops = vec![
+            NextHop {
+                iface_idx: 2,
+                gateway: Some(Ipv4Addr::new(10, 0, 0, 1).into()),
+                weight: 1,
             },
-        )
-        .await
-        .unwrap();
+            NextHop {
+                iface_idx: 3,
+                gateway: None,
+                weight: 256,
+            },
+        ];
+        let multipath = Route {
+            iface_idx: None,
+            gateway: None,
+            nexthops: nexthops.clone(),
+            ..Route::new(0, Ipv4Addr::UNSPECIFIED.into(), 0, None)
+        };
+        assert_eq!(
+            parse_nexthops(&encode_nexthops(&multipath).unwrap()),
+            nexthops
+        );
+        assert_eq!(Scope::from(u8::from(Scope::Link)), Scope::Link);
     }
 }
@@ -0,0 +1,320 @@
+use futures::TryStreamExt;
+use netlink_packet_route::rule::Nla;
+use netlink_packet_route::{RuleMessage, AF_INET, AF_INET6, FR_ACT_TO_TBL};
+use rtnetlink::{Handle, IpVersion};
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::address::ip_from_bytes;
+use crate::{RTNetlinkError, Table};
+
+/// A policy routing rule (`ip rule`). Only rules that look a table up are modelled; every
+/// selector left at `None` matches anything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub ip_version: IpVersion,
+    /// Lower is evaluated first. The kernel picks one when adding without it.
+    pub priority: Option<u32>,
+    pub source: Option<(IpAddr, u8)>,
+    pub destination: Option<(IpAddr, u8)>,
+    pub fwmark: Option<u32>,
+    /// Bits of the mark compared with `fwmark`. All of them by default, so `Some(u32::MAX)`
+    /// is the same as `None` (and reads back as `None`).
+    pub fwmask: Option<u32>,
+    /// Input interface name. `lo` matches locally generated traffic.
+    pub iif: Option<String>,
+    pub oif: Option<String>,
+    pub table: Table,
+    /// Ignore routes from `table` with a prefix this long or shorter (0 ignores default routes).
+    pub suppress_prefixlen: Option<u32>,
+}
+
+impl Rule {
+    /// A rule sending everything of `ip_version` to `table`. Narrow it down with the selectors.
+    pub fn new(ip_version: IpVersion, table: Table) -> Rule {
+        Rule {
+            ip_version,
+            priority: None,
+            source: None,
+            destination: None,
+            fwmark: None,
+            fwmask: None,
+            iif: None,
+            oif: None,
+            table,
+            suppress_prefixlen: None,
+        }
+    }
+
+    /// Read a rule message. `None` for other families and for rules that do something else
+    /// than look a table up (blackhole, goto, ...).
+    pub fn from_message(message: &RuleMessage) -> Option<Rule> {
+        if message.header.action != FR_ACT_TO_TBL {
+            return None;
+        }
+        let ip_version = match message.header.family as u16 {
+            AF_INET => IpVersion::V4,
+            AF_INET6 => IpVersion::V6,
+            _ => return None,
+        };
+
+        let mut rule = Rule::new(ip_version, Table::from(message.header.table as u32));
+        // Priority 0 is not reported.
+        rule.priority = Some(0);
+        for nla in &message.nlas {
+            match nla {
+                Nla::Priority(priority) => rule.priority = Some(*priority),
+                Nla::Source(bytes) => {
+                    rule.source = ip_from_bytes(bytes).map(|ip| (ip, message.header.src_len))
+                }
+                Nla::Destination(bytes) => {
+                    rule.destination = ip_from_bytes(bytes).map(|ip| (ip, message.header.dst_len))
+                }
+                Nla::FwMark(mark) => rule.fwmark = Some(*mark),
+                // A mark without a mask is reported with all bits set.
+                Nla::FwMask(mask) if *mask != u32::MAX => rule.fwmask = Some(*mask),
+                Nla::Iifname(name) => rule.iif = Some(name.clone()),
+                Nla::OifName(name) => rule.oif = Some(name.clone()),
+                // Tables above 255 only fit here.
+                Nla::Table(table) => rule.table = Table::from(*table),
+                // The kernel reports "not set" as all ones.
+                Nla::SuppressPrefixLen(len) if *len != u32::MAX => {
+                    rule.suppress_prefixlen = Some(*len)
+                }
+                _ => {}
+            }
+        }
+        Some(rule)
+    }
+
+    fn to_message(&self) -> Result<RuleMessage, RTNetlinkError> {
+        let mut message = RuleMessage::default();
+        message.header.family = match self.ip_version {
+            IpVersion::V4 => AF_INET as u8,
+            IpVersion::V6 => AF_INET6 as u8,
+        };
+        message.header.action = FR_ACT_TO_TBL;
+        message.header.src_len = self.source.map_or(0, |(_, len)| len);
+        message.header.dst_len = self.destination.map_or(0, |(_, len)| len);
+        message.nlas = self.to_nlas()?;
+        Ok(message)
+    }
+
+    fn to_nlas(&self) -> Result<Vec<Nla>, RTNetlinkError> {
+        let mut nlas = vec![Nla::Table(self.table.into())];
+        if let Some(priority) = self.priority {
+            nlas.push(Nla::Priority(priority));
+        }
+        if let Some((source, _)) = self.source {
+            nlas.push(Nla::Source(self.family_bytes(source)?));
+        }
+        if let Some((destination, _)) = self.destination {
+            nlas.push(Nla::Destination(self.family_bytes(destination)?));
+        }
+        if let Some(mark) = self.fwmark {
+            nlas.push(Nla::FwMark(mark));
+        }
+        if let Some(mask) = self.fwmask {
+            nlas.push(Nla::FwMask(mask));
+        }
+        if let Some(iif) = &self.iif {
+            nlas.push(Nla::Iifname(iif.clone()));
+        }
+        if let Some(oif) = &self.oif {
+            nlas.push(Nla::OifName(oif.clone()));
+        }
+        if let Some(len) = self.suppress_prefixlen {
+            nlas.push(Nla::SuppressPrefixLen(len));
+        }
+        Ok(nlas)
+    }
+
+    fn family_bytes(&self, address: IpAddr) -> Result<Vec<u8>, RTNetlinkError> {
+        match (&self.ip_version, address) {
+            (IpVersion::V4, IpAddr::V4(address)) => Ok(address.octets().to_vec()),
+            (IpVersion::V6, IpAddr::V6(address)) => Ok(address.octets().to_vec()),
+            (IpVersion::V4, address) => Err(RTNetlinkError::invalid(format!(
+                "{} in an IPv4 rule",
+                address
+            ))),
+            (IpVersion::V6, address) => Err(RTNetlinkError::invalid(format!(
+                "{} in an IPv6 rule",
+                address
+            ))),
+        }
+    }
+
+    // Whether `found` (read back from the kernel) is this rule. The kernel fills in the
+    // priority when none was given, so only compare it when we have one.
+    fn matches(&self, found: &Rule) -> bool {
+        let priority = self.priority.is_none() || self.priority == found.priority;
+        priority
+            && Rule {
+                priority: found.priority,
+                fwmask: self.fwmask.filter(|mask| *mask != u32::MAX),
+                ..self.clone()
+            } == *found
+    }
+}
+
+/// Get all policy routing rules of one family.
+pub async fn get_rules(
+    handle: &Handle,
+    ip_version: IpVersion,
+) -> Result<Vec<Rule>, RTNetlinkError> {
+    let messages = rule_messages(handle, ip_version).await?;
+    Ok(messages.iter().filter_map(Rule::from_message).collect())
+}
+
+async fn rule_messages(
+    handle: &Handle,
+    ip_version: IpVersion,
+) -> Result<Vec<RuleMessage>, RTNetlinkError> {
+    handle
+        .rule()
+        .get(ip_version)
+        .execute()
+        .try_collect()
+        .await
+        .map_err(RTNetlinkError::from)
+}
+
+/// `ip rule add ... lookup TABLE`
+pub async fn add_rule(handle: &Handle, rule: &Rule) -> Result<(), RTNetlinkError> {
+    let mut request = handle.rule().add();
+    *request.message_mut() = rule.to_message()?;
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    let rules = get_rules(handle, rule.ip_version.clone()).await?;
+    match rules.iter().any(|found| rule.matches(found)) {
+        true => Ok(()),
+        false => Err(RTNetlinkError::validation(
+            format!("{:?}", rule),
+            "no such rule",
+        )),
+    }
+}
+
+/// Delete every rule matching `rule`.
+pub async fn del_rule(handle: &Handle, rule: &Rule) -> Result<(), RTNetlinkError> {
+    for message in rule_messages(handle, rule.ip_version.clone()).await? {
+        if Rule::from_message(&message).is_some_and(|found| rule.matches(&found)) {
+            handle
+                .rule()
+                .del(message)
+                .execute()
+                .await
+                .map_err(RTNetlinkError::from)?;
+        }
+    }
+
+    // Validate:
+    let rules = get_rules(handle, rule.ip_version.clone()).await?;
+    match rules.iter().any(|found| rule.matches(found)) {
+        true => Err(RTNetlinkError::validation(
+            format!("no rule {:?}", rule),
+            "rule still present",
+        )),
+        false => Ok(()),
+    }
+}
+
+fn source_rule(source: Ipv4Addr, prefix_len: u8, table: u32) -> Rule {
+    Rule {
+        source: Some((source.into(), prefix_len)),
+        ..Rule::new(IpVersion::V4, Table::from(table))
+    }
+}
+
+/// `ip rule add from SOURCE/PREFIX_LEN lookup TABLE priority PRIORITY`
+pub async fn add_source_rule(
+    handle: &Handle,
+    source: Ipv4Addr,
+    prefix_len: u8,
+    table: u32,
+    priority: u32,
+) -> Result<(), RTNetlinkError> {
+    let rule = Rule {
+        priority: Some(priority),
+        ..source_rule(source, prefix_len, table)
+    };
+    add_rule(handle, &rule).await
+}
+
+/// Delete every rule sending SOURCE/PREFIX_LEN to TABLE.
+pub async fn del_source_rule(
+    handle: &Handle,
+    source: Ipv4Addr,
+    prefix_len: u8,
+    table: u32,
+) -> Result<(), RTNetlinkError> {
+    del_rule(handle, &source_rule(source, prefix_len, table)).await
+}
+
+#[cfg(test)]
+mod test_rule {
+    use super::*;
+    use netlink_packet_route::FR_ACT_BLACKHOLE;
+
+    #[test]
+    fn rule_round_trip() {
+        let rule = Rule {
+            priority: Some(1000),
+            source: Some((Ipv4Addr::new(192, 168, 1, 0).into(), 24)),
+            destination: Some((Ipv4Addr::new(10, 0, 0, 0).into(), 8)),
+            fwmark: Some(0x10),
+            fwmask: Some(0xf0),
+            iif: Some("lo".to_string()),
+            oif: Some("eth0".to_string()),
+            suppress_prefixlen: Some(0),
+            ..Rule::new(IpVersion::V4, Table::Id(1000))
+        };
+        let message = rule.to_message().unwrap();
+        assert_eq!(Rule::from_message(&message), Some(rule));
+
+        let mut blackhole = message;
+        blackhole.header.action = FR_ACT_BLACKHOLE;
+        assert_eq!(Rule::from_message(&blackhole), None);
+    }
+
+    #[test]
+    fn full_fwmask_matches_no_fwmask() {
+        let rule = Rule {
+            fwmark: Some(0x10),
+            fwmask: Some(u32::MAX),
+            ..Rule::new(IpVersion::V4, Table::Main)
+        };
+        // The kernel reports a missing mask as all ones too.
+        let found = Rule::from_message(&rule.to_message().unwrap()).unwrap();
+        assert_eq!(found.fwmask, None);
+        assert!(rule.matches(&found));
+    }
+
+    #[test]
+    fn wrong_family_is_invalid() {
+        let rule = Rule {
+            source: Some(("fd00::1".parse().unwrap(), 128)),
+            ..Rule::new(IpVersion::V4, Table::Main)
+        };
+        assert!(matches!(
+            rule.to_message(),
+            Err(RTNetlinkError::InvalidArgument(_))
+        ));
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn add_and_del_rule() {
+        use crate::TestNet;
+
+        let net = TestNet::new().await.unwrap();
+        let rule = Rule {
+            priority: Some(100),
+            fwmark: Some(0x10),
+            fwmask: Some(u32::MAX),
+            ..Rule::new(IpVersion::V4, Table::Id(1000))
+        };
+        add_rule(&net.client_handle, &rule).await.unwrap();
+        del_rule(&net.client_handle, &rule).await.unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+// Throwaway networks for tests. Each `TestNet` is two fresh namespaces, "client" and
+// "server", joined by a veth pair, so tests can add addresses, routes and hardware addresses
+// or run a DHCP exchange without touching the host. Needs root (CAP_SYS_ADMIN and
+// CAP_NET_ADMIN); dnsmasq has to be installed for `start_dhcp_server`.
+use rtnetlink::{Handle, NETNS_PATH};
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use crate::{
+    add_address, add_netns, add_veth, get_link_index, set_link_netns, set_link_up, Address,
+    ConnectionManager, NetNs, RTNetlinkError, POOL_SIZE,
+};
+
+/// Name of the veth end in the client namespace.
+pub const CLIENT_IFACE: &str = "veth0";
+/// Name of the veth end in the server namespace.
+pub const SERVER_IFACE: &str = "veth1";
+
+const DHCP_SERVER_STARTUP: Duration = Duration::from_millis(500);
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+pub struct TestNet {
+    pub client: NetNs,
+    pub server: NetNs,
+    /// Connections to the client namespace. `client_handle` is on its primary one.
+    pub client_connections: ConnectionManager,
+    pub server_connections: ConnectionManager,
+    pub client_handle: Handle,
+    pub server_handle: Handle,
+    /// Index of `CLIENT_IFACE` in the client namespace.
+    pub client_idx: u32,
+    /// Index of `SERVER_IFACE` in the server namespace.
+    pub server_idx: u32,
+    dhcp_server: Option<Child>,
+    // Last, so the namespaces go after everything running in them.
+    namespaces: Namespaces,
+}
+
+impl TestNet {
+    /// Create both namespaces and the veth pair, with both ends (and loopback) up. Needs a
+    /// tokio runtime. Everything is removed again when the `TestNet` is dropped.
+    pub async fn new() -> Result<TestNet, RTNetlinkError> {
+        // Unique across the tests of one run and across concurrent runs.
+        let id = format!(
+            "local_net-test-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut namespaces = Namespaces(Vec::new());
+        for side in ["client", "server"] {
+            let name = format!("{}-{}", id, side);
+            add_netns(&name).await?;
+            namespaces.0.push(name);
+        }
+        let client = NetNs::Named(namespaces.0[0].clone());
+        let server = NetNs::Named(namespaces.0[1].clone());
+        let client_connections = ConnectionManager::new(client.clone(), POOL_SIZE);
+        let server_connections = ConnectionManager::new(server.clone(), POOL_SIZE);
+        let client_handle = client_connections.handle()?;
+        let server_handle = server_connections.handle()?;
+
+        // Created in the client namespace, where both names are free, then one end moves.
+        let (client_idx, peer_idx) = add_veth(&client_handle, CLIENT_IFACE, SERVER_IFACE).await?;
+        set_link_netns(&client_handle, peer_idx, &server).await?;
+        let server_idx = get_link_index(&server_handle, SERVER_IFACE).await?;
+
+        for handle in [&client_handle, &server_handle] {
+            set_link_up(handle, get_link_index(handle, "lo").await?).await?;
+        }
+        set_link_up(&client_handle, client_idx).await?;
+        set_link_up(&server_handle, server_idx).await?;
+
+        Ok(TestNet {
+            client,
+            server,
+            client_handle,
+            server_handle,
+            client_idx,
+            server_idx,
+            client_connections,
+            server_connections,
+            dhcp_server: None,
+            namespaces,
+        })
+    }
+
+    /// Give the server end `address/prefix_len` and serve leases from `range` on it.
+    pub async fn start_dhcp_server(
+        &mut self,
+        address: Ipv4Addr,
+        prefix_len: u8,
+        range: (Ipv4Addr, Ipv4Addr),
+    ) -> Result<(), RTNetlinkError> {
+        let server_address = Address::new(self.server_idx, IpAddr::V4(address), prefix_len);
+        add_address(&self.server_handle, &server_address).await?;
+
+        let mut child = Command::new("ip")
+            .args(["netns", "exec", &self.namespaces.0[1], "dnsmasq"])
+            .args([
+                "--keep-in-foreground",
+                "--conf-file=/dev/null",
+                "--port=0",
+                "--bind-interfaces",
+                "--dhcp-authoritative",
+                "--dhcp-leasefile=/dev/null",
+            ])
+            .arg(format!("--interface={}", SERVER_IFACE))
+            .arg(format!("--dhcp-range={},{},2m", range.0, range.1))
+            .arg(format!("--dhcp-option=option:router,{}", address))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        // Give it time to bind; a DISCOVER sent before that is lost.
+        tokio::time::sleep(DHCP_SERVER_STARTUP).await;
+
+        // `ip netns exec` exits right away if dnsmasq is missing, and dnsmasq exits if it
+        // can't bind.
+        match child.try_wait()? {
+            None => {
+                self.dhcp_server = Some(child);
+                Ok(())
+            }
+            Some(status) => Err(RTNetlinkError::IOError(std::io::Error::other(format!(
+                "dnsmasq exited during startup ({}); is it installed?",
+                status
+            )))),
+        }
+    }
+}
+
+impl Drop for TestNet {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.dhcp_server.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// Named namespaces that are removed on drop, also when `TestNet::new` fails halfway.
+struct Namespaces(Vec<String>);
+
+impl Drop for Namespaces {
+    fn drop(&mut self) {
+        // What `del_netns` does, without needing a runtime. The veth pair goes with the
+        // namespaces.
+        for name in &self.0 {
+            let path = Path::new(NETNS_PATH).join(name);
+            if let Ok(c_path) = CString::new(path.to_string_lossy().as_bytes()) {
+                unsafe { libc::umount2(c_path.as_ptr(), libc::MNT_DETACH) };
+            }
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -1,56 +1,83 @@
-use futures::TryStreamExt;
-use rtnetlink::{new_connection, Handle};
+use futures::Future;
+use netlink_packet_route::AddressMessage;
+use rtnetlink::Handle;
 
-/// Flush all addresses from an interface.
-pub async fn flush_addresses(handle: &Handle, iface_idx: u32) -> Result<(), crate::RTNetlinkError> {
-    let mut request = handle
-        .address()
-        .get()
-        .set_link_index_filter(iface_idx)
-        .execute();
-
-    #[cfg(not(experimental = "threading"))]
-    {
-        let (connection, delete_handle, _) = new_connection().unwrap();
-        tokio::spawn(connection);
-        while let Some(address) = request
-            .try_next()
-            .await
-            .map_err(crate::RTNetlinkError::RTNetlink)?
-        {
-            crate::del_address(&delete_handle, address).await?
-        }
-    }
+use crate::address::address_messages;
+use crate::{get_addresses, RTNetlinkError};
 
-    #[cfg(experimental = "threading")]
-    {
-        use futures::future::join_all;
+// Run `op` on every item in order and stop at the first error. An item can depend on the
+// ones before it (a gateway route on the route to its subnet). Pipelining the requests
+// measured no faster: the kernel handles rtnetlink requests one at a time.
+pub(crate) async fn run_all<T, F, Fut>(
+    items: impl IntoIterator<Item = T>,
+    mut op: F,
+) -> Result<(), RTNetlinkError>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = Result<(), RTNetlinkError>>,
+{
+    for item in items {
+        op(item).await?;
+    }
+    Ok(())
+}
 
-        let futures = Vec::new();
-        while let Some(address) = request
-            .try_next()
-            .await
-            .map_err(RTNetlinkError::RTNetlink)?
-        {
-            let (connection, delete_handle, _) = new_connection().unwrap();
-            del_address(&delete_handle, address);
-            futures.push(tokio::spawn(connection));
-        }
-        join_all(futures).await?;
+async fn del_address_message(
+    handle: &Handle,
+    message: AddressMessage,
+) -> Result<(), RTNetlinkError> {
+    match handle.address().del(message).execute().await {
+        Ok(()) => Ok(()),
+        Err(e) => match RTNetlinkError::from(e) {
+            // Already gone: secondaries go with their primary.
+            e if e.errno() == Some(libc::EADDRNOTAVAIL) => Ok(()),
+            e => Err(e),
+        },
     }
+}
+
+/// Flush all addresses from an interface.
+pub async fn flush_addresses(handle: &Handle, iface_idx: u32) -> Result<(), RTNetlinkError> {
+    // Delete with the messages from one dump instead of looking each address up again.
+    let messages = address_messages(handle, iface_idx).await?;
+    run_all(messages, |message| del_address_message(handle, message)).await?;
 
     // Validation
-    let res_address = handle
-        .address()
-        .get()
-        .set_link_index_filter(iface_idx)
-        .execute()
-        .try_next()
-        .await
-        .map_err(crate::RTNetlinkError::RTNetlink)?;
-
-    match res_address {
-        Some(_) => Err(crate::RTNetlinkError::ValidationFailed),
+    let res_addresses = get_addresses(handle, iface_idx).await?;
+
+    match res_addresses.first() {
+        Some(address) => Err(RTNetlinkError::validation(
+            format!("no addresses on link {}", iface_idx),
+            format!("{}/{}", address.address, address.prefix_len),
+        )),
         None => Ok(()),
     }
 }
+
+#[cfg(all(test, feature = "testbed"))]
+mod test_utils {
+    use super::*;
+    use crate::{add_addresses, Address, TestNet};
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn add_and_flush_many_addresses() {
+        let net = TestNet::new().await.unwrap();
+        let addresses: Vec<Address> = (0..500u32)
+            .map(|i| Address::new(net.client_idx, Ipv4Addr::from(0x0a64_0000 + i).into(), 32))
+            .collect();
+
+        let handle = &net.client_handle;
+        add_addresses(handle, &addresses).await.unwrap();
+        let added = get_addresses(handle, net.client_idx).await.unwrap();
+        // Next to the IPv6 link-local address.
+        let added = added.iter().filter(|address| address.address.is_ipv4());
+        assert_eq!(added.count(), addresses.len());
+
+        flush_addresses(handle, net.client_idx).await.unwrap();
+        assert!(get_addresses(handle, net.client_idx)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
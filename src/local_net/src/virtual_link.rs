@@ -0,0 +1,385 @@
+use netlink_packet_route::link::nlas::{Info, InfoData, InfoIpVlan, InfoKind, Nla};
+use netlink_packet_route::{
+    IFF_UP, MACVLAN_MODE_BRIDGE, MACVLAN_MODE_PASSTHRU, MACVLAN_MODE_PRIVATE, MACVLAN_MODE_VEPA,
+};
+use rtnetlink::{Handle, LinkAddRequest};
+
+use crate::{get_link, get_link_index, get_link_message, set_link_up, RTNetlinkError};
+
+// Not exported by netlink-packet-route (linux/if_link.h).
+const IPVLAN_MODE_L2: u16 = 0;
+const IPVLAN_MODE_L3: u16 = 1;
+const IPVLAN_MODE_L3S: u16 = 2;
+const IPVLAN_F_PRIVATE: u16 = 1;
+const IPVLAN_F_VEPA: u16 = 2;
+
+/// How macvlan/ipvlan children of the same parent may talk to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Isolation {
+    /// Children reach each other directly, without leaving the host.
+    Bridge,
+    /// Children can't reach each other at all.
+    Private,
+    /// Traffic between children goes out to the switch and has to be reflected back.
+    Vepa,
+    /// macvlan only: the single child takes over the parent's address.
+    Passthru,
+}
+
+impl Isolation {
+    fn macvlan_mode(&self) -> u32 {
+        match self {
+            Isolation::Bridge => MACVLAN_MODE_BRIDGE,
+            Isolation::Private => MACVLAN_MODE_PRIVATE,
+            Isolation::Vepa => MACVLAN_MODE_VEPA,
+            Isolation::Passthru => MACVLAN_MODE_PASSTHRU,
+        }
+    }
+
+    fn ipvlan_flags(&self) -> Option<u16> {
+        match self {
+            Isolation::Bridge => Some(0),
+            Isolation::Private => Some(IPVLAN_F_PRIVATE),
+            Isolation::Vepa => Some(IPVLAN_F_VEPA),
+            Isolation::Passthru => None,
+        }
+    }
+}
+
+/// The layer ipvlan children are switched at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpVlanMode {
+    L2,
+    L3,
+    /// L3 with netfilter (conntrack) on the child.
+    L3S,
+}
+
+impl IpVlanMode {
+    fn value(&self) -> u16 {
+        match self {
+            IpVlanMode::L2 => IPVLAN_MODE_L2,
+            IpVlanMode::L3 => IPVLAN_MODE_L3,
+            IpVlanMode::L3S => IPVLAN_MODE_L3S,
+        }
+    }
+}
+
+// Send the request, then look the new link up by name to validate and get its index.
+async fn add_link(
+    handle: &Handle,
+    request: LinkAddRequest,
+    name: &str,
+) -> Result<u32, RTNetlinkError> {
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    match get_link_index(handle, name).await {
+        Ok(iface_idx) => Ok(iface_idx),
+        Err(RTNetlinkError::InterfaceNotFound) => Err(RTNetlinkError::validation(
+            format!("a link named {}", name),
+            "none",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// Create a dummy link (`ip link add NAME type dummy`).
+pub async fn add_dummy(handle: &Handle, name: &str) -> Result<u32, RTNetlinkError> {
+    let request = handle.link().add().dummy(name.to_string());
+    add_link(handle, request, name).await
+}
+
+/// Create a veth pair. Returns the indexes of `name` and `peer`.
+pub async fn add_veth(
+    handle: &Handle,
+    name: &str,
+    peer: &str,
+) -> Result<(u32, u32), RTNetlinkError> {
+    let request = handle.link().add().veth(name.to_string(), peer.to_string());
+    let iface_idx = add_link(handle, request, name).await?;
+    let peer_idx = get_link_index(handle, peer).await?;
+    Ok((iface_idx, peer_idx))
+}
+
+/// Create a macvlan child of `parent_idx`. It gets its own random hardware address.
+pub async fn add_macvlan(
+    handle: &Handle,
+    parent_idx: u32,
+    name: &str,
+    isolation: Isolation,
+) -> Result<u32, RTNetlinkError> {
+    let request =
+        handle
+            .link()
+            .add()
+            .macvlan(name.to_string(), parent_idx, isolation.macvlan_mode());
+    add_link(handle, request, name).await
+}
+
+/// Create an ipvlan child of `parent_idx`. Unlike macvlan it shares the parent's hardware
+/// address, which is what some wireless drivers and switch port security insist on.
+pub async fn add_ipvlan(
+    handle: &Handle,
+    parent_idx: u32,
+    name: &str,
+    mode: IpVlanMode,
+    isolation: Isolation,
+) -> Result<u32, RTNetlinkError> {
+    let flags = isolation.ipvlan_flags().ok_or_else(|| {
+        RTNetlinkError::invalid(format!(
+            "{:?} isolation is not available for ipvlan",
+            isolation
+        ))
+    })?;
+
+    // rtnetlink has no ipvlan builder, so build it like `macvlan` does.
+    let mut request = handle.link().add();
+    let message = request.message_mut();
+    message.header.flags |= IFF_UP;
+    message.header.change_mask |= IFF_UP;
+    message.nlas.push(Nla::IfName(name.to_string()));
+    message.nlas.push(Nla::Link(parent_idx));
+    message.nlas.push(Nla::Info(vec![
+        Info::Kind(InfoKind::IpVlan),
+        Info::Data(InfoData::IpVlan(vec![
+            InfoIpVlan::Mode(mode.value()),
+            InfoIpVlan::Flags(flags),
+        ])),
+    ]));
+    add_link(handle, request, name).await
+}
+
+/// Create an 802.1Q vlan on `parent_idx`.
+pub async fn add_vlan(
+    handle: &Handle,
+    parent_idx: u32,
+    name: &str,
+    vlan_id: u16,
+) -> Result<u32, RTNetlinkError> {
+    if vlan_id == 0 || vlan_id >= 4095 {
+        return Err(RTNetlinkError::invalid(format!(
+            "vlan id {} is not in 1..4095",
+            vlan_id
+        )));
+    }
+
+    let request = handle
+        .link()
+        .add()
+        .vlan(name.to_string(), parent_idx, vlan_id);
+    add_link(handle, request, name).await
+}
+
+/// Create a bridge and bring it up.
+pub async fn add_bridge(handle: &Handle, name: &str) -> Result<u32, RTNetlinkError> {
+    let request = handle.link().add().bridge(name.to_string());
+    let iface_idx = add_link(handle, request, name).await?;
+    set_link_up(handle, iface_idx).await?;
+    Ok(iface_idx)
+}
+
+/// Enslave a link to a bridge (or bond).
+pub async fn set_link_master(
+    handle: &Handle,
+    iface_idx: u32,
+    master_idx: u32,
+) -> Result<(), RTNetlinkError> {
+    let request = handle.link().set(iface_idx).master(master_idx);
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    let found = get_link(handle, iface_idx).await?.master;
+    match found == Some(master_idx) {
+        true => Ok(()),
+        false => Err(RTNetlinkError::validation(
+            format!("link {} enslaved to {}", iface_idx, master_idx),
+            format!("{:?}", found),
+        )),
+    }
+}
+
+/// Release a link from its bridge (or bond).
+pub async fn set_link_nomaster(handle: &Handle, iface_idx: u32) -> Result<(), RTNetlinkError> {
+    let request = handle.link().set(iface_idx).nomaster();
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    match get_link(handle, iface_idx).await?.master {
+        None => Ok(()),
+        Some(master) => Err(RTNetlinkError::validation(
+            format!("link {} without master", iface_idx),
+            format!("master {}", master),
+        )),
+    }
+}
+
+/// Delete a link. Deleting one end of a veth pair deletes both.
+pub async fn del_link(handle: &Handle, iface_idx: u32) -> Result<(), RTNetlinkError> {
+    let request = handle.link().del(iface_idx);
+    request.execute().await.map_err(RTNetlinkError::from)?;
+
+    // Validate:
+    match get_link_message(handle, iface_idx).await {
+        Err(RTNetlinkError::InterfaceNotFound) => Ok(()),
+        Ok(_) => Err(RTNetlinkError::validation(
+            format!("link {} deleted", iface_idx),
+            "still present",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test_virtual_link {
+    use super::*;
+
+    #[test]
+    fn passthru_is_macvlan_only() {
+        assert_eq!(Isolation::Passthru.ipvlan_flags(), None);
+        assert_eq!(Isolation::Passthru.macvlan_mode(), MACVLAN_MODE_PASSTHRU);
+        assert_eq!(Isolation::Private.ipvlan_flags(), Some(IPVLAN_F_PRIVATE));
+    }
+
+    // Kernels built without a link kind's driver answer EOPNOTSUPP; skip those.
+    #[cfg(feature = "testbed")]
+    fn supported(result: Result<u32, RTNetlinkError>) -> Option<u32> {
+        match result {
+            Err(e) if e.errno() == Some(libc::EOPNOTSUPP) => None,
+            result => Some(result.unwrap()),
+        }
+    }
+
+    #[cfg(feature = "testbed")]
+    async fn assert_deleted(handle: &Handle, iface_idx: u32) {
+        del_link(handle, iface_idx).await.unwrap();
+        assert!(matches!(
+            get_link(handle, iface_idx).await,
+            Err(RTNetlinkError::InterfaceNotFound)
+        ));
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn veth() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let (iface_idx, peer_idx) = add_veth(handle, "vtest0", "vtest1").await.unwrap();
+        assert_eq!(get_link(handle, peer_idx).await.unwrap().name, "vtest1");
+
+        // Deleting one end takes the peer along.
+        assert_deleted(handle, iface_idx).await;
+        assert!(matches!(
+            get_link(handle, peer_idx).await,
+            Err(RTNetlinkError::InterfaceNotFound)
+        ));
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn dummy() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        if let Some(iface_idx) = supported(add_dummy(handle, "dtest0").await) {
+            assert_eq!(get_link(handle, iface_idx).await.unwrap().name, "dtest0");
+            assert_deleted(handle, iface_idx).await;
+        }
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn macvlan() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let parent = get_link(handle, net.client_idx).await.unwrap();
+        let request = add_macvlan(handle, parent.index, "mvtest0", Isolation::Bridge).await;
+        if let Some(iface_idx) = supported(request) {
+            // A macvlan gets its own hardware address.
+            let link = get_link(handle, iface_idx).await.unwrap();
+            assert_ne!(link.address, parent.address);
+            assert_deleted(handle, iface_idx).await;
+        }
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn ipvlan() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let parent = get_link(handle, net.client_idx).await.unwrap();
+        let request = add_ipvlan(
+            handle,
+            parent.index,
+            "ivtest0",
+            IpVlanMode::L2,
+            Isolation::Private,
+        )
+        .await;
+        if let Some(iface_idx) = supported(request) {
+            // An ipvlan shares its parent's.
+            let link = get_link(handle, iface_idx).await.unwrap();
+            assert_eq!(link.address, parent.address);
+            assert_deleted(handle, iface_idx).await;
+        }
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn vlan() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let request = add_vlan(handle, net.client_idx, "vlantest0", 7).await;
+        if let Some(iface_idx) = supported(request) {
+            assert_eq!(get_link(handle, iface_idx).await.unwrap().name, "vlantest0");
+            assert_deleted(handle, iface_idx).await;
+        }
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn bridge() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let bridge_idx = add_bridge(handle, "brtest0").await.unwrap();
+        assert!(get_link(handle, bridge_idx).await.unwrap().up);
+
+        set_link_master(handle, net.client_idx, bridge_idx)
+            .await
+            .unwrap();
+        set_link_nomaster(handle, net.client_idx).await.unwrap();
+        assert_deleted(handle, bridge_idx).await;
+    }
+
+    #[cfg(feature = "testbed")]
+    #[tokio::test]
+    async fn reject_invalid_arguments() {
+        let net = crate::TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let parent_idx = net.client_idx;
+
+        for vlan_id in [0, 4095] {
+            assert!(matches!(
+                add_vlan(handle, parent_idx, "vlantest0", vlan_id).await,
+                Err(RTNetlinkError::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            add_ipvlan(
+                handle,
+                parent_idx,
+                "ivtest0",
+                IpVlanMode::L3,
+                Isolation::Passthru
+            )
+            .await,
+            Err(RTNetlinkError::InvalidArgument(_))
+        ));
+        // Rejected before anything was sent.
+        for name in ["vlantest0", "ivtest0"] {
+            assert!(matches!(
+                get_link_index(handle, name).await,
+                Err(RTNetlinkError::InterfaceNotFound)
+            ));
+        }
+    }
+}
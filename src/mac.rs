@@ -1,7 +1,16 @@
-use libc::{c_char, c_int, sockaddr, ARPHRD_ETHER};
-use netdevice::{get_hardware, set_hardware};
+use libc::{c_char, c_int};
+use local_net::{get_link_address, get_link_index, get_permanent_address, set_link_address, NetNs};
+use netdevice::get_hardware;
 use pnet::util::MacAddr;
-use std::io::Error;
+use rtnetlink::Handle;
+use std::collections::HashMap;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Mutex, OnceLock};
+
+use crate::error::Error;
+use crate::mac_addr::MacAddrExt;
+use crate::mac_generator::{random_mac, DeviceClass};
+use crate::oui::{OuiDatabase, Vendor};
 
 pub fn new_socket() -> Result<c_int, Error> {
     use libc::{AF_INET, IPPROTO_UDP, SOCK_DGRAM};
@@ -9,42 +18,232 @@ pub fn new_socket() -> Result<c_int, Error> {
     let res = unsafe { libc::socket(AF_INET, SOCK_DGRAM, IPPROTO_UDP) };
 
     match res {
-        -1 => Err(Error::last_os_error()),
+        -1 => Err(Error::Io(std::io::Error::last_os_error())),
         sock => Ok(sock),
     }
 }
 
-// This should really have some unit tests, but without a docker
-// container it would be weird.
-pub fn set_mac(interface: &str, mac: MacAddr) {
-    let mut old = get_hardware(new_socket().unwrap(), interface)
-        .unwrap()
-        .sa_data;
+// Addresses interfaces had before we first changed them, keyed by namespace id and
+// interface index: the same name can exist in several namespaces.
+fn original_macs() -> &'static Mutex<HashMap<(u64, u32), MacAddr>> {
+    static ORIGINAL_MACS: OnceLock<Mutex<HashMap<(u64, u32), MacAddr>>> = OnceLock::new();
+    ORIGINAL_MACS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn netns_id(ns: &NetNs, interface: &str) -> Result<u64, Error> {
+    ns.id().map_err(|e| Error::from_netlink(interface, e))
+}
+
+/// The address interface `iface_idx` in `ns` had before our first change to it, if we
+/// changed it.
+pub fn original_mac(ns: &NetNs, iface_idx: u32) -> Result<Option<MacAddr>, Error> {
+    let key = (netns_id(ns, &format!("link {}", iface_idx))?, iface_idx);
+    Ok(original_macs().lock().unwrap().get(&key).copied())
+}
+
+/// Give the interface `mac`. The address it had before is remembered for `restore_mac`.
+pub async fn set_mac(handle: &Handle, interface: &str, mac: MacAddr) -> Result<(), Error> {
+    set_mac_in(&NetNs::Current, handle, interface, mac).await
+}
+
+/// Like `set_mac`, for an interface in `ns`. `handle` has to be a handle on `ns`.
+pub async fn set_mac_in(
+    ns: &NetNs,
+    handle: &Handle,
+    interface: &str,
+    mac: MacAddr,
+) -> Result<(), Error> {
+    let ns_id = netns_id(ns, interface)?;
+    let iface_idx = get_link_index(handle, interface)
+        .await
+        .map_err(|e| Error::from_netlink(interface, e))?;
+    let current = get_link_address(handle, iface_idx)
+        .await
+        .map_err(|e| Error::from_netlink(interface, e))?;
+
+    if let Some(current) = MacAddr::from_slice(&current) {
+        original_macs()
+            .lock()
+            .unwrap()
+            .entry((ns_id, iface_idx))
+            .or_insert(current);
+    }
+
+    // set_link_address validates the change itself.
+    set_link_address(handle, iface_idx, &mac.to_bytes())
+        .await
+        .map_err(|e| Error::from_netlink(interface, e))
+}
+
+/// Put back the address the interface had before we first changed it. If we never changed
+/// it, fall back to the permanent address.
+pub async fn restore_mac(handle: &Handle, interface: &str) -> Result<MacAddr, Error> {
+    restore_mac_in(&NetNs::Current, handle, interface).await
+}
+
+/// Like `restore_mac`, for an interface in `ns`. `handle` has to be a handle on `ns`.
+pub async fn restore_mac_in(
+    ns: &NetNs,
+    handle: &Handle,
+    interface: &str,
+) -> Result<MacAddr, Error> {
+    let iface_idx = get_link_index(handle, interface)
+        .await
+        .map_err(|e| Error::from_netlink(interface, e))?;
+    let target = match original_mac(ns, iface_idx)? {
+        Some(mac) => mac,
+        None => permanent_mac(ns, handle, iface_idx, interface)
+            .await?
+            .ok_or_else(|| {
+                Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} has no recorded or permanent address", interface),
+                ))
+            })?,
+    };
+
+    set_mac_in(ns, handle, interface, target).await?;
+    let key = (netns_id(ns, interface)?, iface_idx);
+    original_macs().lock().unwrap().remove(&key);
+    Ok(target)
+}
+
+// Ask netlink first: it answers for the namespace of `handle`. The ioctl only sees the
+// namespace we run in, so it is only a fallback when that is `ns`.
+async fn permanent_mac(
+    ns: &NetNs,
+    handle: &Handle,
+    iface_idx: u32,
+    interface: &str,
+) -> Result<Option<MacAddr>, Error> {
+    let address = get_permanent_address(handle, iface_idx)
+        .await
+        .map_err(|e| Error::from_netlink(interface, e))?;
+    if let Some(mac) = address.as_deref().and_then(MacAddr::from_slice) {
+        return Ok(Some(mac));
+    }
+    match netns_id(ns, interface)? == netns_id(&NetNs::Current, interface)? {
+        true => get_permanent_mac(interface),
+        false => Ok(None),
+    }
+}
+
+const SIOCETHTOOL: u32 = 0x8946;
+const ETHTOOL_GPERMADDR: u32 = 0x20;
+const MAX_ADDR_LEN: usize = 32;
+
+#[repr(C)]
+struct EthtoolPermAddr {
+    cmd: u32,
+    size: u32,
+    data: [u8; MAX_ADDR_LEN],
+}
+
+// struct ifreq with only the ifr_data member of the union. The padding keeps the size
+// equal to the kernel's (the union is 24 bytes).
+#[repr(C)]
+struct IfreqData {
+    ifr_name: [c_char; libc::IFNAMSIZ],
+    ifr_data: *mut libc::c_void,
+    _pad: [u8; 16],
+}
 
-    let new_addr: [u8; 6] = [mac.0, mac.1, mac.2, mac.3, mac.4, mac.5];
-    for i in 0..6 {
-        old[i] = new_addr[i] as c_char;
+/// Get the permanent (burned-in) address through ETHTOOL_GPERMADDR. Returns `None` if the
+/// driver doesn't know it (common for virtual interfaces).
+pub fn get_permanent_mac(interface: &str) -> Result<Option<MacAddr>, Error> {
+    if interface.len() >= libc::IFNAMSIZ {
+        return Err(Error::InterfaceNotFound(interface.to_string()));
     }
 
-    let sock = sockaddr {
-        sa_family: ARPHRD_ETHER,
-        sa_data: old,
+    let mut perm_addr = EthtoolPermAddr {
+        cmd: ETHTOOL_GPERMADDR,
+        size: MAX_ADDR_LEN as u32,
+        data: [0; MAX_ADDR_LEN],
     };
+    let mut ifr = IfreqData {
+        ifr_name: [0; libc::IFNAMSIZ],
+        ifr_data: &mut perm_addr as *mut EthtoolPermAddr as *mut libc::c_void,
+        _pad: [0; 16],
+    };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(interface.bytes()) {
+        *dst = src as c_char;
+    }
 
-    set_hardware(new_socket().unwrap(), interface, sock).unwrap();
+    let sock = unsafe { OwnedFd::from_raw_fd(new_socket()?) };
+    let res = unsafe { libc::ioctl(sock.as_raw_fd(), SIOCETHTOOL as _, &mut ifr) };
+    if res == -1 {
+        let e = std::io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) => Ok(None),
+            _ => Err(Error::from_io(interface, e)),
+        };
+    }
+
+    let size = (perm_addr.size as usize).min(MAX_ADDR_LEN);
+    Ok(MacAddr::from_slice(&perm_addr.data[..size]).filter(|mac| mac.to_u64() != 0))
+}
+
+/// The current hardware address of an interface (SIOCGIFHWADDR).
+pub fn get_mac(interface: &str) -> Result<MacAddr, Error> {
+    let sock = unsafe { OwnedFd::from_raw_fd(new_socket()?) };
+    let res =
+        get_hardware(sock.as_raw_fd(), interface).map_err(|e| Error::from_io(interface, e))?;
+
+    // sa_data is c_char, the address is in its first 6 bytes.
+    let data: Vec<u8> = res.sa_data.iter().map(|&b| b as u8).collect();
+    MacAddr::from_slice(&data).ok_or_else(|| Error::InterfaceNotFound(interface.to_string()))
 }
 
-pub fn mac_convert_to_bia(mac: MacAddr) -> MacAddr {
-    let mut new_addr: [u8; 6] = [mac.0, mac.1, mac.2, mac.3, mac.4, mac.5];
-    new_addr[0] &= 0xfc;
-    new_addr[0] |= 0x02;
-    MacAddr::new(new_addr[0], new_addr[1], new_addr[2], new_addr[3], new_addr[4], new_addr[5])
+/// The vendor an address will appear to belong to.
+pub fn get_vendor(mac: MacAddr, db: &OuiDatabase) -> Option<&Vendor> {
+    db.lookup(mac)
 }
 
-pub fn get_mac(interface: &str) -> [u8; 14] {
-    let res = get_hardware(new_socket().unwrap(), interface).unwrap();
+/// Give the interface a random address from a real vendor block for `class`.
+pub async fn set_random_mac(
+    handle: &Handle,
+    interface: &str,
+    db: &OuiDatabase,
+    class: Option<DeviceClass>,
+) -> Result<MacAddr, Error> {
+    let mac = random_mac(db, class).ok_or_else(|| {
+        Error::InvalidDatabase(format!("No vendor in the database matches {:?}", class))
+    })?;
+    set_mac(handle, interface, mac).await?;
+    Ok(mac)
+}
+
+#[cfg(all(test, feature = "testbed"))]
+mod test_mac {
+    use super::*;
+    use local_net::{TestNet, CLIENT_IFACE};
+
+    #[tokio::test]
+    async fn set_and_restore_mac() {
+        let net = TestNet::new().await.unwrap();
+        let handle = &net.client_handle;
+        let before = get_link_address(handle, net.client_idx).await.unwrap();
 
-    // i8 -> u8 is very safe. I hope to god the compiler
-    // doesn't actually preform any additional computation.
-    unsafe { std::mem::transmute(res.sa_data) }
+        let mac = MacAddr::new(0x02, 0x00, 0x5e, 0x10, 0x20, 0x30);
+        set_mac_in(&net.client, handle, CLIENT_IFACE, mac)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_link_address(handle, net.client_idx).await.unwrap(),
+            mac.to_bytes()
+        );
+        // Recorded for the client namespace only.
+        let recorded = original_mac(&net.client, net.client_idx).unwrap();
+        assert_eq!(
+            recorded.map(|mac| mac.to_bytes().to_vec()),
+            Some(before.clone())
+        );
+        assert_eq!(original_mac(&net.server, net.client_idx).unwrap(), None);
+
+        let restored = restore_mac_in(&net.client, handle, CLIENT_IFACE)
+            .await
+            .unwrap();
+        assert_eq!(restored.to_bytes().to_vec(), before);
+        assert_eq!(original_mac(&net.client, net.client_idx).unwrap(), None);
+    }
 }
@@ -0,0 +1,201 @@
+// Helpers on top of pnet's `MacAddr`. pnet already answers is_unicast/is_multicast/
+// is_local/is_universal/is_broadcast, this adds the rest of what we need to treat addresses
+// as numbers: OUI/NIC parts, bit conversions, parsing and formatting.
+use pnet::util::MacAddr;
+
+use crate::error::Error;
+
+/// Individual/group bit. Set for multicast.
+const IG_BIT: u8 = 0x01;
+/// Universal/local bit. Set for locally administered addresses.
+const UL_BIT: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacClass {
+    Broadcast,
+    Multicast,
+    /// A unicast address assigned by a vendor (burned in).
+    UniversalUnicast,
+    /// A unicast address somebody picked, like ours after randomization.
+    LocalUnicast,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacFormat {
+    /// 00:1b:c5:0a:0b:0c, what `ip link` prints.
+    Colon,
+    /// 00-1B-C5-0A-0B-0C, what Windows prints.
+    Hyphen,
+    /// 001b.c50a.0b0c, what Cisco prints.
+    Dotted,
+    /// 001bc50a0b0c
+    Bare,
+}
+
+pub trait MacAddrExt: Sized {
+    /// Build an address from the first 6 bytes of a link-layer address. `None` if it is
+    /// shorter than that.
+    fn from_slice(bytes: &[u8]) -> Option<Self>;
+    fn from_u64(value: u64) -> Self;
+    /// Combine a 24 bit OUI with a 24 bit NIC specific part.
+    fn from_parts(oui: u32, nic: u32) -> Self;
+    /// Parse any of the `MacFormat`s, in either case.
+    fn parse(text: &str) -> Result<Self, Error>;
+
+    fn to_bytes(&self) -> [u8; 6];
+    fn to_u64(&self) -> u64;
+    /// The first 3 bytes. Meaningless for locally administered addresses.
+    fn oui(&self) -> u32;
+    /// The last 3 bytes.
+    fn nic(&self) -> u32;
+    fn class(&self) -> MacClass;
+
+    /// Set the U/L bit, keeping everything else.
+    fn to_local(&self) -> Self;
+    /// Clear the U/L bit, keeping everything else.
+    fn to_universal(&self) -> Self;
+    /// Clear the I/G bit, keeping everything else.
+    fn to_unicast(&self) -> Self;
+
+    fn format(&self, format: MacFormat) -> String;
+}
+
+impl MacAddrExt for MacAddr {
+    fn from_slice(bytes: &[u8]) -> Option<MacAddr> {
+        match bytes {
+            [a, b, c, d, e, f, ..] => Some(MacAddr::new(*a, *b, *c, *d, *e, *f)),
+            _ => None,
+        }
+    }
+
+    fn from_u64(value: u64) -> MacAddr {
+        let bytes = value.to_be_bytes();
+        MacAddr::new(bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7])
+    }
+
+    fn from_parts(oui: u32, nic: u32) -> MacAddr {
+        MacAddr::from_u64(((oui as u64 & 0xffffff) << 24) | (nic as u64 & 0xffffff))
+    }
+
+    fn parse(text: &str) -> Result<MacAddr, Error> {
+        let invalid = || Error::InvalidAddress(text.to_string());
+        let text = text.trim();
+
+        let digits: String = match text.len() {
+            // 00:1b:c5:0a:0b:0c or 00-1b-c5-0a-0b-0c, one separator kind throughout.
+            17 => {
+                let separator = text.as_bytes()[2] as char;
+                if separator != ':' && separator != '-' {
+                    return Err(invalid());
+                }
+                let groups: Vec<&str> = text.split(separator).collect();
+                if groups.len() != 6 || groups.iter().any(|group| group.len() != 2) {
+                    return Err(invalid());
+                }
+                groups.concat()
+            }
+            // 001b.c50a.0b0c
+            14 => {
+                let groups: Vec<&str> = text.split('.').collect();
+                if groups.len() != 3 || groups.iter().any(|group| group.len() != 4) {
+                    return Err(invalid());
+                }
+                groups.concat()
+            }
+            12 => text.to_string(),
+            _ => return Err(invalid()),
+        };
+
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        u64::from_str_radix(&digits, 16)
+            .map(MacAddr::from_u64)
+            .map_err(|_| invalid())
+    }
+
+    fn to_bytes(&self) -> [u8; 6] {
+        [self.0, self.1, self.2, self.3, self.4, self.5]
+    }
+
+    fn to_u64(&self) -> u64 {
+        self.to_bytes()
+            .iter()
+            .fold(0, |acc, &byte| (acc << 8) | byte as u64)
+    }
+
+    fn oui(&self) -> u32 {
+        (self.to_u64() >> 24) as u32
+    }
+
+    fn nic(&self) -> u32 {
+        (self.to_u64() & 0xffffff) as u32
+    }
+
+    fn class(&self) -> MacClass {
+        if self.to_u64() == 0xffff_ffff_ffff {
+            MacClass::Broadcast
+        } else if self.0 & IG_BIT != 0 {
+            MacClass::Multicast
+        } else if self.0 & UL_BIT != 0 {
+            MacClass::LocalUnicast
+        } else {
+            MacClass::UniversalUnicast
+        }
+    }
+
+    fn to_local(&self) -> MacAddr {
+        MacAddr::new(self.0 | UL_BIT, self.1, self.2, self.3, self.4, self.5)
+    }
+
+    fn to_universal(&self) -> MacAddr {
+        MacAddr::new(self.0 & !UL_BIT, self.1, self.2, self.3, self.4, self.5)
+    }
+
+    fn to_unicast(&self) -> MacAddr {
+        MacAddr::new(self.0 & !IG_BIT, self.1, self.2, self.3, self.4, self.5)
+    }
+
+    fn format(&self, format: MacFormat) -> String {
+        let b = self.to_bytes();
+        match format {
+            MacFormat::Colon => self.to_string(),
+            MacFormat::Hyphen => format!(
+                "{:02X}-{:02X}-{:02X}-{:02X}-{:02X}-{:02X}",
+                b[0], b[1], b[2], b[3], b[4], b[5]
+            ),
+            MacFormat::Dotted => format!(
+                "{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}",
+                b[0], b[1], b[2], b[3], b[4], b[5]
+            ),
+            MacFormat::Bare => format!("{:012x}", self.to_u64()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_mac_addr {
+    use super::*;
+
+    #[test]
+    fn parse_and_format_round_trip() {
+        let mac = MacAddr::new(0x00, 0x1b, 0xc5, 0x0a, 0x0b, 0x0c);
+        for format in [
+            MacFormat::Colon,
+            MacFormat::Hyphen,
+            MacFormat::Dotted,
+            MacFormat::Bare,
+        ] {
+            assert_eq!(MacAddr::parse(&mac.format(format)).unwrap(), mac);
+        }
+        assert!(MacAddr::parse("00:1b:c5-0a:0b:0c").is_err());
+        assert!(MacAddr::parse("00:1b:c5:0a:0b").is_err());
+
+        assert_eq!(mac.oui(), 0x001bc5);
+        assert_eq!(mac.nic(), 0x0a0b0c);
+        assert_eq!(MacAddr::from_parts(mac.oui(), mac.nic()), mac);
+        assert_eq!(mac.class(), MacClass::UniversalUnicast);
+        assert_eq!(mac.to_local().class(), MacClass::LocalUnicast);
+        assert_eq!(mac.to_local().to_universal(), mac);
+    }
+}
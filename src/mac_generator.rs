@@ -0,0 +1,116 @@
+// Random MAC addresses that look like real hardware. Instead of flipping the
+// locally-administered bit, we take a real vendor block from the OUI database and
+// randomize only the NIC specific part.
+use pnet::util::MacAddr;
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::mac_addr::MacAddrExt;
+use crate::oui::{BlockType, OuiDatabase, Vendor};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceClass {
+    Router,
+    Desktop,
+    Laptop,
+    Phone,
+    Iot,
+    Printer,
+}
+
+impl DeviceClass {
+    pub const ALL: [DeviceClass; 6] = [
+        DeviceClass::Router,
+        DeviceClass::Desktop,
+        DeviceClass::Laptop,
+        DeviceClass::Phone,
+        DeviceClass::Iot,
+        DeviceClass::Printer,
+    ];
+
+    /// Upper case fragments of vendor names that commonly ship this class of device.
+    fn vendor_keywords(&self) -> &'static [&'static str] {
+        match self {
+            DeviceClass::Router => &[
+                "CISCO",
+                "JUNIPER",
+                "ROUTERBOARD",
+                "NETGEAR",
+                "TP-LINK",
+                "UBIQUITI",
+                "LINKSYS",
+                "ASUSTEK",
+                "HUAWEI",
+            ],
+            DeviceClass::Desktop => &["INTEL", "DELL", "HEWLETT", "REALTEK", "ASUSTEK"],
+            DeviceClass::Laptop => &["INTEL", "APPLE", "DELL", "LCFC", "HEWLETT", "MICROSOFT"],
+            DeviceClass::Phone => &["APPLE", "SAMSUNG", "ONEPLUS", "XIAOMI", "GOOGLE", "HUAWEI"],
+            DeviceClass::Iot => &[
+                "ESPRESSIF",
+                "RASPBERRY",
+                "AMAZON",
+                "SONOS",
+                "NEST",
+                "PHILIPS LIGHTING",
+            ],
+            DeviceClass::Printer => &["EPSON", "BROTHER", "CANON", "HEWLETT"],
+        }
+    }
+
+    pub fn matches(&self, vendor: &Vendor) -> bool {
+        let name = vendor.name.to_uppercase();
+        self.vendor_keywords()
+            .iter()
+            .any(|keyword| name.contains(keyword))
+    }
+}
+
+/// Vendors whose blocks can be used for a device of `class` (or any device if `None`).
+pub fn candidate_vendors(db: &OuiDatabase, class: Option<DeviceClass>) -> Vec<&Vendor> {
+    db.vendors()
+        // Company IDs are never used for universally administered addresses.
+        .filter(|vendor| vendor.block != BlockType::Cid)
+        .filter(|vendor| class.is_none_or(|class| class.matches(vendor)))
+        .collect()
+}
+
+/// Generate a random unicast, universally administered address for a device of `class`.
+/// Returns `None` if the database has no vendor for that class.
+pub fn random_mac(db: &OuiDatabase, class: Option<DeviceClass>) -> Option<MacAddr> {
+    let mut vendors = candidate_vendors(db, class);
+    // HashMap iteration order is random per process, sort so the choice only depends on rng.
+    vendors.sort_by_key(|vendor| (vendor.block.prefix_bits(), vendor.prefix));
+
+    let vendor = vendors.choose(&mut rand::thread_rng())?;
+    Some(random_mac_from(vendor))
+}
+
+/// Generate a random address inside `vendor`'s block.
+pub fn random_mac_from(vendor: &Vendor) -> MacAddr {
+    let nic_bits = 48 - vendor.block.prefix_bits() as u64;
+    let nic_mask = (1u64 << nic_bits) - 1;
+
+    // An all zero NIC part looks like a placeholder, skip it.
+    let nic = rand::thread_rng().gen_range(1..=nic_mask);
+    let mac = MacAddr::from_u64((vendor.prefix << nic_bits) | nic);
+
+    // Clear the multicast (I/G) and locally administered (U/L) bits. A real block never has
+    // them set, but a bad database entry shouldn't make us emit a multicast source address.
+    mac.to_unicast().to_universal()
+}
+
+#[cfg(test)]
+mod test_mac_generator {
+    use super::*;
+    use crate::mac_addr::MacClass;
+
+    #[test]
+    fn generated_addresses_match_class() {
+        let db = OuiDatabase::embedded();
+        for class in DeviceClass::ALL {
+            let mac = random_mac(db, Some(class)).unwrap();
+            assert_eq!(mac.class(), MacClass::UniversalUnicast, "{}", mac);
+            assert!(class.matches(db.lookup(mac).unwrap()));
+        }
+    }
+}
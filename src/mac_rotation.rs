@@ -0,0 +1,246 @@
+// Scheduled MAC rotation. Every rotation hands the network over from the old identity to
+// the new one: release the lease, change the address, forget the neighbours we learned,
+// get a new lease and put the routes back. If the new identity doesn't come up we go back
+// to the old one.
+use local_net::{flush_neighbours, get_link_address, get_link_index, get_link_routes};
+use local_net::{restore_link_routes, Route};
+use pnet::util::MacAddr;
+use rtnetlink::Handle;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::error::Error;
+use crate::lease::{apply_lease, remove_lease, Lease};
+use crate::mac::set_mac;
+use crate::mac_addr::MacAddrExt;
+use crate::mac_generator::{random_mac, DeviceClass};
+use crate::oui::OuiDatabase;
+use crate::send_dhcp::{obtain_lease, release_lease};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RotationPolicy {
+    /// Rotate once the address has been in use for this long.
+    Every(Duration),
+    /// Rotate whenever the link comes back up.
+    OnReconnect,
+    /// Only rotate when `MacRotator::rotate` is called.
+    OnDemand,
+}
+
+/// Where new addresses come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacSource {
+    /// A random address from a vendor in the embedded database.
+    Random(Option<DeviceClass>),
+    Fixed(MacAddr),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RotationStep {
+    ReleasedLease(Lease),
+    /// The lease was given up, but the server wasn't told. It expires there on its own.
+    ReleaseFailed {
+        lease: Lease,
+        reason: String,
+    },
+    ChangedMac(MacAddr),
+    FlushedNeighbours,
+    ObtainedLease(Lease),
+    AppliedLease,
+    RestoredRoutes,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RotationOutcome {
+    Rotated,
+    /// The new identity failed and the old one was brought back.
+    RolledBack(String),
+    /// The new identity failed, and so did going back. The interface is in an unknown state.
+    RollbackFailed {
+        reason: String,
+        rollback: String,
+    },
+}
+
+/// Everything one rotation did, reported as a single event.
+#[derive(Clone, Debug)]
+pub struct RotationEvent {
+    pub interface: String,
+    pub old_mac: MacAddr,
+    pub new_mac: MacAddr,
+    pub old_lease: Option<Lease>,
+    pub new_lease: Option<Lease>,
+    /// Steps in the order they were taken, including the rollback.
+    pub steps: Vec<RotationStep>,
+    pub outcome: RotationOutcome,
+}
+
+pub struct MacRotator {
+    handle: Handle,
+    interface: String,
+    policy: RotationPolicy,
+    source: MacSource,
+    lease: Option<Lease>,
+    last_rotation: Instant,
+}
+
+impl MacRotator {
+    /// `lease` is the lease the interface currently holds, if any. It is released on the
+    /// first rotation.
+    pub fn new(
+        handle: Handle,
+        interface: &str,
+        policy: RotationPolicy,
+        source: MacSource,
+        lease: Option<Lease>,
+    ) -> MacRotator {
+        MacRotator {
+            handle,
+            interface: interface.to_string(),
+            policy,
+            source,
+            lease,
+            last_rotation: Instant::now(),
+        }
+    }
+
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Whether the policy wants a rotation now.
+    pub fn due(&self) -> bool {
+        match self.policy {
+            RotationPolicy::Every(interval) => self.last_rotation.elapsed() >= interval,
+            RotationPolicy::OnReconnect | RotationPolicy::OnDemand => false,
+        }
+    }
+
+    /// Call when the link came back up. Rotates if the policy asks for it.
+    pub async fn on_reconnect(&mut self) -> Result<Option<RotationEvent>, Error> {
+        match self.policy {
+            RotationPolicy::OnReconnect => Ok(Some(self.rotate().await?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Rotate on the policy's schedule, sending every rotation to `events`. Returns when the
+    /// receiver is dropped. Does nothing for policies without a schedule.
+    pub async fn run(mut self, events: mpsc::Sender<RotationEvent>) -> Result<(), Error> {
+        let interval = match self.policy {
+            RotationPolicy::Every(interval) => interval,
+            _ => return Ok(()),
+        };
+
+        loop {
+            tokio::time::sleep_until((self.last_rotation + interval).into()).await;
+            let event = self.rotate().await?;
+            if events.send(event).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Move the interface to a new address. Failures of the new identity are reported in the
+    /// event's outcome, only errors before anything was changed are returned.
+    pub async fn rotate(&mut self) -> Result<RotationEvent, Error> {
+        let iface_idx = get_link_index(&self.handle, &self.interface)
+            .await
+            .map_err(|e| Error::from_netlink(&self.interface, e))?;
+        let current = get_link_address(&self.handle, iface_idx)
+            .await
+            .map_err(|e| Error::from_netlink(&self.interface, e))?;
+        let old_mac = MacAddr::from_slice(&current).ok_or_else(|| {
+            Error::InterfaceNotFound(format!("{} has no ethernet address", self.interface))
+        })?;
+        let new_mac = self.next_mac()?;
+        // Routes go away with the address, so remember them first.
+        let routes = get_link_routes(&self.handle, iface_idx)
+            .await
+            .map_err(|e| Error::from_netlink(&self.interface, e))?;
+
+        let old_lease = self.lease.clone();
+        let mut steps = Vec::new();
+        self.last_rotation = Instant::now();
+
+        let outcome = match self.switch(iface_idx, new_mac, &routes, &mut steps).await {
+            Ok(()) => RotationOutcome::Rotated,
+            Err(reason) => match self.switch(iface_idx, old_mac, &routes, &mut steps).await {
+                Ok(()) => RotationOutcome::RolledBack(reason.to_string()),
+                Err(rollback) => RotationOutcome::RollbackFailed {
+                    reason: reason.to_string(),
+                    rollback: rollback.to_string(),
+                },
+            },
+        };
+
+        Ok(RotationEvent {
+            interface: self.interface.clone(),
+            old_mac,
+            new_mac,
+            old_lease,
+            new_lease: self.lease.clone(),
+            steps,
+            outcome,
+        })
+    }
+
+    fn next_mac(&self) -> Result<MacAddr, Error> {
+        match self.source {
+            MacSource::Fixed(mac) => Ok(mac),
+            MacSource::Random(class) => {
+                random_mac(OuiDatabase::embedded(), class).ok_or_else(|| {
+                    Error::InvalidDatabase(format!("No vendor in the database matches {:?}", class))
+                })
+            }
+        }
+    }
+
+    // Hand the interface over to `mac`, giving up whatever lease we hold.
+    async fn switch(
+        &mut self,
+        iface_idx: u32,
+        mac: MacAddr,
+        routes: &[Route],
+        steps: &mut Vec<RotationStep>,
+    ) -> Result<(), Error> {
+        if let Some(lease) = self.lease.take() {
+            // The server will expire it eventually, so a lost release is not fatal.
+            let released = release_lease(&lease);
+            remove_lease(&self.handle, iface_idx, &lease).await?;
+            steps.push(match released {
+                Ok(()) => RotationStep::ReleasedLease(lease),
+                Err(e) => RotationStep::ReleaseFailed {
+                    lease,
+                    reason: e.to_string(),
+                },
+            });
+        }
+
+        set_mac(&self.handle, &self.interface, mac).await?;
+        steps.push(RotationStep::ChangedMac(mac));
+
+        // Entries learned under the old identity would make us answer from it.
+        flush_neighbours(&self.handle, iface_idx)
+            .await
+            .map_err(|e| Error::from_netlink(&self.interface, e))?;
+        steps.push(RotationStep::FlushedNeighbours);
+
+        let interface = self.interface.clone();
+        let lease = tokio::task::spawn_blocking(move || obtain_lease(&interface))
+            .await
+            .map_err(|e| Error::Io(std::io::Error::other(e)))??;
+        steps.push(RotationStep::ObtainedLease(lease.clone()));
+
+        // Keep the lease even if applying it fails so it gets released on rollback.
+        self.lease = Some(lease.clone());
+        apply_lease(&self.handle, iface_idx, &lease).await?;
+        steps.push(RotationStep::AppliedLease);
+
+        restore_link_routes(&self.handle, iface_idx, routes.to_vec())
+            .await
+            .map_err(|e| Error::from_netlink(&self.interface, e))?;
+        steps.push(RotationStep::RestoredRoutes);
+        Ok(())
+    }
+}
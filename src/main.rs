@@ -1,7 +1,17 @@
 mod dhcp;
+mod error;
+mod identities;
+mod lease;
 mod mac;
+mod mac_addr;
+mod mac_generator;
+mod mac_rotation;
+mod oui;
+mod responder;
 mod send_dhcp;
+mod stable_mac;
 mod subnet_manager;
+mod uplinks;
 mod user_interface;
 
 #[tokio::main]
@@ -0,0 +1,235 @@
+// Vendor (OUI) database. Loads either the maclookup.app JSON export
+// (https://maclookup.app/downloads/json-database) or the IEEE registry CSV
+// (https://standards-oui.ieee.org/). A small IEEE-format snapshot is embedded so
+// lookups work without any file on disk.
+use pnet::util::MacAddr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::error::Error;
+use crate::mac_addr::MacAddrExt;
+
+const EMBEDDED_SNAPSHOT: &str = include_str!("../data/oui.csv");
+
+/// IEEE registry an assignment belongs to. The registry decides how many leading bits of
+/// an address identify the vendor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlockType {
+    /// MA-L, the classic 24 bit OUI.
+    Large,
+    /// MA-M, 28 bits.
+    Medium,
+    /// MA-S (and the older IAB), 36 bits.
+    Small,
+    /// Company ID. Never used in universally administered addresses.
+    Cid,
+}
+
+impl BlockType {
+    pub fn prefix_bits(&self) -> u8 {
+        match self {
+            BlockType::Large | BlockType::Cid => 24,
+            BlockType::Medium => 28,
+            BlockType::Small => 36,
+        }
+    }
+
+    fn from_registry(registry: &str) -> Option<BlockType> {
+        match registry.trim() {
+            "MA-L" => Some(BlockType::Large),
+            "MA-M" => Some(BlockType::Medium),
+            "MA-S" | "IAB" => Some(BlockType::Small),
+            "CID" => Some(BlockType::Cid),
+            _ => None,
+        }
+    }
+
+    fn from_prefix_bits(bits: u8) -> Option<BlockType> {
+        match bits {
+            24 => Some(BlockType::Large),
+            28 => Some(BlockType::Medium),
+            36 => Some(BlockType::Small),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vendor {
+    pub name: String,
+    pub block: BlockType,
+    /// The assigned prefix, right aligned (I.E. 0x00000c for Cisco's 00:00:0C).
+    pub prefix: u64,
+}
+
+impl Vendor {
+    /// The lowest address inside this vendor's block.
+    pub fn base_address(&self) -> MacAddr {
+        let shift = 48 - self.block.prefix_bits() as u64;
+        MacAddr::from_u64(self.prefix << shift)
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct OuiDatabase {
+    // Keyed by (prefix length in bits, prefix)
+    vendors: HashMap<(u8, u64), Vendor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MacLookupEntry {
+    mac_prefix: String,
+    vendor_name: String,
+    block_type: Option<String>,
+}
+
+impl OuiDatabase {
+    /// The snapshot compiled into the binary. Parsed once on first use.
+    pub fn embedded() -> &'static OuiDatabase {
+        static EMBEDDED: OnceLock<OuiDatabase> = OnceLock::new();
+        EMBEDDED.get_or_init(|| {
+            OuiDatabase::from_ieee_csv(EMBEDDED_SNAPSHOT).expect("Embedded OUI snapshot is invalid")
+        })
+    }
+
+    /// Load a database from disk, guessing the format from the contents.
+    pub fn load(path: &Path) -> Result<OuiDatabase, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        match contents.trim_start().starts_with('[') {
+            true => OuiDatabase::from_maclookup_json(&contents),
+            false => OuiDatabase::from_ieee_csv(&contents),
+        }
+    }
+
+    /// Parse the maclookup.app JSON export.
+    pub fn from_maclookup_json(json: &str) -> Result<OuiDatabase, Error> {
+        let entries: Vec<MacLookupEntry> =
+            serde_json::from_str(json).map_err(|e| Error::InvalidDatabase(e.to_string()))?;
+
+        let mut db = OuiDatabase::default();
+        for entry in entries {
+            let (prefix, bits) = parse_prefix(&entry.mac_prefix)?;
+            let block = entry
+                .block_type
+                .as_deref()
+                .and_then(BlockType::from_registry)
+                .or_else(|| BlockType::from_prefix_bits(bits))
+                .ok_or_else(|| {
+                    Error::InvalidDatabase(format!("Unknown block size for {}", entry.mac_prefix))
+                })?;
+            db.insert(entry.vendor_name, block, prefix);
+        }
+        Ok(db)
+    }
+
+    /// Parse the IEEE registry CSV (Registry,Assignment,Organization Name,Organization Address).
+    pub fn from_ieee_csv(csv_data: &str) -> Result<OuiDatabase, Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .from_reader(csv_data.as_bytes());
+
+        let mut db = OuiDatabase::default();
+        for record in reader.records() {
+            let record = record.map_err(|e| Error::InvalidDatabase(e.to_string()))?;
+            let (registry, assignment, name) = match (record.get(0), record.get(1), record.get(2)) {
+                (Some(registry), Some(assignment), Some(name)) => (registry, assignment, name),
+                _ => {
+                    return Err(Error::InvalidDatabase(format!(
+                        "Short record: {:?}",
+                        record
+                    )))
+                }
+            };
+
+            // Registries we don't understand can't tell us how long the prefix is.
+            let block = match BlockType::from_registry(registry) {
+                Some(block) => block,
+                None => continue,
+            };
+            let (prefix, _) = parse_prefix(assignment)?;
+            db.insert(name.trim().to_string(), block, prefix);
+        }
+        Ok(db)
+    }
+
+    fn insert(&mut self, name: String, block: BlockType, prefix: u64) {
+        self.vendors.insert(
+            (block.prefix_bits(), prefix),
+            Vendor {
+                name,
+                block,
+                prefix,
+            },
+        );
+    }
+
+    /// Find the vendor an address was assigned to. Longer (more specific) blocks win.
+    pub fn lookup(&self, mac: MacAddr) -> Option<&Vendor> {
+        let value = mac.to_u64();
+        [36u8, 28, 24]
+            .iter()
+            .find_map(|&bits| self.vendors.get(&(bits, value >> (48 - bits))))
+    }
+
+    pub fn vendors(&self) -> impl Iterator<Item = &Vendor> {
+        self.vendors.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.vendors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vendors.is_empty()
+    }
+}
+
+/// Parse a prefix like "00:1B:C5:0", "00-1B-C5" or "001BC5" into (prefix, bits).
+fn parse_prefix(text: &str) -> Result<(u64, u8), Error> {
+    let digits: String = text.chars().filter(|c| c.is_ascii_hexdigit()).collect();
+    if digits.is_empty() || digits.len() > 12 {
+        return Err(Error::InvalidDatabase(format!("Invalid prefix {}", text)));
+    }
+
+    let prefix = u64::from_str_radix(&digits, 16)
+        .map_err(|_| Error::InvalidDatabase(format!("Invalid prefix {}", text)))?;
+    Ok((prefix, digits.len() as u8 * 4))
+}
+
+#[cfg(test)]
+mod test_oui {
+    use super::*;
+
+    #[test]
+    fn embedded_lookup() {
+        let db = OuiDatabase::embedded();
+        let vendor = db
+            .lookup(MacAddr::new(0x00, 0x00, 0x0c, 0x12, 0x34, 0x56))
+            .unwrap();
+        assert_eq!(vendor.name, "Cisco Systems, Inc");
+        assert!(db.lookup(MacAddr::new(0x02, 0, 0, 0, 0, 1)).is_none());
+    }
+
+    #[test]
+    fn maclookup_json_prefers_longest_prefix() {
+        let db = OuiDatabase::from_maclookup_json(
+            r#"[
+                {"macPrefix":"70:B3:D5","vendorName":"IEEE Registration Authority","private":false,"blockType":"MA-L","lastUpdate":"2016/04/13"},
+                {"macPrefix":"70:B3:D5:00:0","vendorName":"Small Vendor","private":false,"blockType":"MA-S","lastUpdate":"2016/04/13"}
+            ]"#,
+        )
+        .unwrap();
+
+        let small = MacAddr::new(0x70, 0xb3, 0xd5, 0x00, 0x0f, 0xff);
+        let large = MacAddr::new(0x70, 0xb3, 0xd5, 0x10, 0x00, 0x00);
+        assert_eq!(db.lookup(small).unwrap().name, "Small Vendor");
+        assert_eq!(
+            db.lookup(large).unwrap().name,
+            "IEEE Registration Authority"
+        );
+    }
+}
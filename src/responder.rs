@@ -0,0 +1,531 @@
+// Extra identities on one interface without kernel sub-interfaces, for drivers that refuse
+// macvlan (most Wi-Fi drivers only accept their own address). The interface is put in
+// promiscuous mode and a thread sorts incoming frames by identity: ARP requests for an
+// identity's address are answered here, DHCP replies are routed by chaddr and everything
+// else addressed to an identity is queued for it.
+use local_net::{in_netns, NetNs};
+use log::warn;
+use pnet::datalink::{self, Channel, DataLinkSender, NetworkInterface};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::packet::udp::UdpPacket;
+use pnet::packet::{MutablePacket, Packet};
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::lease::Lease;
+use crate::mac_addr::{MacAddrExt, MacClass};
+use crate::send_dhcp::{obtain_lease_over, FrameTransport};
+
+/// Frames queued per identity before new ones are dropped.
+const INBOX_LEN: usize = 256;
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+const ARP_FRAME_LEN: usize = 42;
+
+struct IdentityState {
+    address: Option<Ipv4Addr>,
+    inbox: SyncSender<Vec<u8>>,
+    // Frames dropped since the inbox last had room.
+    dropped: u64,
+}
+
+type Identities = Arc<Mutex<HashMap<MacAddr, IdentityState>>>;
+type Sender = Arc<Mutex<Box<dyn DataLinkSender>>>;
+
+pub struct Responder {
+    interface: NetworkInterface,
+    tx: Sender,
+    identities: Identities,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Responder {
+    /// Start answering for identities on `interface_name`. Identities are added with
+    /// `add_identity`.
+    pub fn start(interface_name: &str) -> Result<Responder, Error> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface_name)
+            .ok_or_else(|| Error::InterfaceNotFound(interface_name.to_string()))?;
+
+        // Frames for the identities are not addressed to the NIC, so it has to pass them up.
+        let config = datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            promiscuous: true,
+            ..Default::default()
+        };
+        let (tx, mut rx) = match datalink::channel(&interface, config) {
+            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
+            Ok(_) => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unknown channel type",
+                )))
+            }
+            Err(e) => return Err(Error::from_io(interface_name, e)),
+        };
+
+        let tx: Sender = Arc::new(Mutex::new(tx));
+        let identities: Identities = Arc::new(Mutex::new(HashMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let (tx, identities, stop) = (tx.clone(), identities.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match rx.next() {
+                        Ok(frame) => dispatch(frame, &tx, &identities),
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                        Err(e) => {
+                            warn!("Responder stopped: {}", e);
+                            return;
+                        }
+                    }
+                }
+            })
+        };
+
+        Ok(Responder {
+            interface,
+            tx,
+            identities,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Like `start`, for an interface in another network namespace. The capture socket stays
+    /// there; the thread reading it doesn't have to.
+    pub fn start_in(ns: &NetNs, interface_name: &str) -> Result<Responder, Error> {
+        in_netns(ns, || Responder::start(interface_name))?
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface.name
+    }
+
+    /// Start answering for `mac`. Until it has an address, only frames addressed to `mac` and
+    /// DHCP replies for it are delivered.
+    pub fn add_identity(&self, mac: MacAddr) -> Result<VirtualIdentity, Error> {
+        if !matches!(
+            mac.class(),
+            MacClass::UniversalUnicast | MacClass::LocalUnicast
+        ) || mac.to_u64() == 0
+            || Some(mac) == self.interface.mac
+        {
+            return Err(Error::InvalidAddress(mac.to_string()));
+        }
+
+        let (inbox_tx, inbox) = mpsc::sync_channel(INBOX_LEN);
+        let mut identities = self.identities.lock().unwrap();
+        if identities.contains_key(&mac) {
+            return Err(Error::InvalidAddress(format!("{} is already in use", mac)));
+        }
+        identities.insert(
+            mac,
+            IdentityState {
+                address: None,
+                inbox: inbox_tx,
+                dropped: 0,
+            },
+        );
+
+        Ok(VirtualIdentity {
+            mac,
+            tx: self.tx.clone(),
+            identities: self.identities.clone(),
+            inbox,
+        })
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// One identity answered for by a `Responder`. Removed again when dropped.
+pub struct VirtualIdentity {
+    mac: MacAddr,
+    tx: Sender,
+    identities: Identities,
+    inbox: Receiver<Vec<u8>>,
+}
+
+impl VirtualIdentity {
+    pub fn address(&self) -> Option<Ipv4Addr> {
+        self.identities
+            .lock()
+            .unwrap()
+            .get(&self.mac)
+            .and_then(|state| state.address)
+    }
+
+    /// Claim `address`: answer ARP for it and announce it with a gratuitous ARP.
+    pub fn set_address(&mut self, address: Option<Ipv4Addr>) -> Result<(), Error> {
+        if let Some(state) = self.identities.lock().unwrap().get_mut(&self.mac) {
+            state.address = address;
+        }
+
+        match address {
+            Some(address) => {
+                let frame = build_arp(
+                    ArpOperations::Request,
+                    self.mac,
+                    address,
+                    MacAddr::broadcast(),
+                    address,
+                );
+                send(&self.tx, &frame)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Get a lease for this identity and start answering ARP for it.
+    pub fn obtain_lease(&mut self) -> Result<Lease, Error> {
+        let lease = obtain_lease_over(self)?;
+        self.set_address(Some(lease.address))?;
+        Ok(lease)
+    }
+}
+
+impl FrameTransport for VirtualIdentity {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    /// Send a frame as this identity. The source address is overwritten with ours.
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        let mut frame = frame.to_vec();
+        match MutableEthernetPacket::new(&mut frame) {
+            Some(mut packet) => packet.set_source(self.mac),
+            None => return Err(Error::Io(std::io::Error::other("Frame is too short"))),
+        }
+        send(&self.tx, &frame)
+    }
+
+    fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.inbox.recv_timeout(READ_TIMEOUT) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::Io(std::io::Error::other("Responder is gone")))
+            }
+        }
+    }
+}
+
+impl Drop for VirtualIdentity {
+    fn drop(&mut self) {
+        self.identities.lock().unwrap().remove(&self.mac);
+    }
+}
+
+fn send(tx: &Sender, frame: &[u8]) -> Result<(), Error> {
+    match tx.lock().unwrap().send_to(frame, None) {
+        Some(Err(e)) => Err(Error::Io(e)),
+        Some(Ok(())) => Ok(()),
+        None => Err(Error::Io(std::io::Error::other(
+            "Datalink channel did not send the packet",
+        ))),
+    }
+}
+
+fn deliver(mac: MacAddr, state: &mut IdentityState, frame: &[u8]) {
+    // A full inbox means nobody is reading, drop rather than stall every identity. Warned
+    // about once when it fills up and once when it has room again, not per frame.
+    match state.inbox.try_send(frame.to_vec()) {
+        Ok(()) if state.dropped > 0 => {
+            warn!("Dropped {} frames for {}", state.dropped, mac);
+            state.dropped = 0;
+        }
+        Err(TrySendError::Full(_)) => {
+            if state.dropped == 0 {
+                warn!("Inbox of {} is full, dropping frames", mac);
+            }
+            state.dropped += 1;
+        }
+        _ => {}
+    }
+}
+
+fn dispatch(frame: &[u8], tx: &Sender, identities: &Identities) {
+    let ethernet_packet = match EthernetPacket::new(frame) {
+        Some(packet) => packet,
+        None => return,
+    };
+    let mut identities = identities.lock().unwrap();
+
+    if ethernet_packet.get_ethertype() == EtherTypes::Arp {
+        if let Some(arp) = ArpPacket::new(ethernet_packet.payload()) {
+            answer_arp(&arp, tx, &identities);
+        }
+    }
+
+    let destination = ethernet_packet.get_destination();
+    if let Some(state) = identities.get_mut(&destination) {
+        deliver(destination, state, frame);
+        return;
+    }
+    if destination != MacAddr::broadcast() {
+        return;
+    }
+
+    // Broadcast DHCP replies belong to whoever's chaddr they carry.
+    if let Some(chaddr) = dhcp_reply_chaddr(&ethernet_packet) {
+        if let Some(state) = identities.get_mut(&chaddr) {
+            deliver(chaddr, state, frame);
+        }
+        return;
+    }
+    for (&mac, state) in identities.iter_mut() {
+        deliver(mac, state, frame);
+    }
+}
+
+fn answer_arp(arp: &ArpPacket, tx: &Sender, identities: &HashMap<MacAddr, IdentityState>) {
+    if arp.get_operation() != ArpOperations::Request {
+        return;
+    }
+
+    let target = arp.get_target_proto_addr();
+    let owner = identities
+        .iter()
+        .find(|(_, state)| state.address == Some(target));
+    if let Some((&mac, _)) = owner {
+        let reply = build_arp(
+            ArpOperations::Reply,
+            mac,
+            target,
+            arp.get_sender_hw_addr(),
+            arp.get_sender_proto_addr(),
+        );
+        if let Err(e) = send(tx, &reply) {
+            warn!("Failed to answer ARP for {}: {}", target, e);
+        }
+    }
+}
+
+fn dhcp_reply_chaddr(ethernet_packet: &EthernetPacket) -> Option<MacAddr> {
+    let ipv4_packet = Ipv4Packet::new(ethernet_packet.payload())?;
+    if ipv4_packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp_packet = UdpPacket::new(ipv4_packet.payload())?;
+    if udp_packet.get_destination() != 68 {
+        return None;
+    }
+    // chaddr is at offset 28 of the DHCP message.
+    MacAddr::from_slice(udp_packet.payload().get(28..34)?)
+}
+
+fn build_arp(
+    operation: pnet::packet::arp::ArpOperation,
+    sender_mac: MacAddr,
+    sender_ip: Ipv4Addr,
+    target_mac: MacAddr,
+    target_ip: Ipv4Addr,
+) -> Vec<u8> {
+    let mut frame = vec![0u8; ARP_FRAME_LEN];
+    {
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        ethernet_packet.set_destination(target_mac);
+        ethernet_packet.set_source(sender_mac);
+        ethernet_packet.set_ethertype(EtherTypes::Arp);
+
+        let mut arp_packet = MutableArpPacket::new(ethernet_packet.payload_mut()).unwrap();
+        arp_packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_packet.set_protocol_type(EtherTypes::Ipv4);
+        arp_packet.set_hw_addr_len(6);
+        arp_packet.set_proto_addr_len(4);
+        arp_packet.set_operation(operation);
+        arp_packet.set_sender_hw_addr(sender_mac);
+        arp_packet.set_sender_proto_addr(sender_ip);
+        // Requests (gratuitous ARP) leave the target hardware address zeroed.
+        arp_packet.set_target_hw_addr(if operation == ArpOperations::Reply {
+            target_mac
+        } else {
+            MacAddr::zero()
+        });
+        arp_packet.set_target_proto_addr(target_ip);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod test_responder {
+    use super::*;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::udp::MutableUdpPacket;
+
+    // Keeps what would have gone out on the wire.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl DataLinkSender for Recorder {
+        fn build_and_send(
+            &mut self,
+            num_packets: usize,
+            packet_size: usize,
+            func: &mut dyn FnMut(&mut [u8]),
+        ) -> Option<std::io::Result<()>> {
+            for _ in 0..num_packets {
+                let mut packet = vec![0u8; packet_size];
+                func(&mut packet);
+                self.0.lock().unwrap().push(packet);
+            }
+            Some(Ok(()))
+        }
+
+        fn send_to(
+            &mut self,
+            packet: &[u8],
+            _dst: Option<NetworkInterface>,
+        ) -> Option<std::io::Result<()>> {
+            self.0.lock().unwrap().push(packet.to_vec());
+            Some(Ok(()))
+        }
+    }
+
+    const SERVER: MacAddr = MacAddr(0x00, 0x00, 0x0c, 0, 0, 1);
+    const FIRST: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 1);
+    const SECOND: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 2);
+
+    // A responder's state without the capture thread, with `FIRST` claiming 192.168.1.10
+    // and `SECOND` without an address yet.
+    fn setup() -> (Sender, Recorder, Identities, [Receiver<Vec<u8>>; 2]) {
+        let recorder = Recorder::default();
+        let tx: Sender = Arc::new(Mutex::new(Box::new(recorder.clone())));
+        let identities: Identities = Arc::new(Mutex::new(HashMap::new()));
+        let inboxes = [
+            (FIRST, Some(Ipv4Addr::new(192, 168, 1, 10))),
+            (SECOND, None),
+        ]
+        .map(|(mac, address)| {
+            let (inbox_tx, inbox) = mpsc::sync_channel(INBOX_LEN);
+            let state = IdentityState {
+                address,
+                inbox: inbox_tx,
+                dropped: 0,
+            };
+            identities.lock().unwrap().insert(mac, state);
+            inbox
+        });
+        (tx, recorder, identities, inboxes)
+    }
+
+    fn dhcp_reply(destination: MacAddr, chaddr: MacAddr) -> Vec<u8> {
+        let mut dhcp = vec![0u8; 240];
+        // BOOTREPLY
+        dhcp[0] = 2;
+        dhcp[28..34].copy_from_slice(&chaddr.to_bytes());
+        let udp_len = 8 + dhcp.len();
+        let ip_len = 20 + udp_len;
+
+        let mut frame = vec![0u8; 14 + ip_len];
+        let mut ethernet_packet = MutableEthernetPacket::new(&mut frame).unwrap();
+        ethernet_packet.set_destination(destination);
+        ethernet_packet.set_source(SERVER);
+        ethernet_packet.set_ethertype(EtherTypes::Ipv4);
+        let mut ipv4_packet = MutableIpv4Packet::new(ethernet_packet.payload_mut()).unwrap();
+        ipv4_packet.set_version(4);
+        ipv4_packet.set_header_length(5);
+        ipv4_packet.set_total_length(ip_len as u16);
+        ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let mut udp_packet = MutableUdpPacket::new(ipv4_packet.payload_mut()).unwrap();
+        udp_packet.set_source(67);
+        udp_packet.set_destination(68);
+        udp_packet.set_length(udp_len as u16);
+        udp_packet.set_payload(&dhcp);
+        frame
+    }
+
+    #[test]
+    fn unicast_goes_to_its_identity() {
+        let (tx, _, identities, [first, second]) = setup();
+        let frame = dhcp_reply(SECOND, SECOND);
+        dispatch(&frame, &tx, &identities);
+        assert_eq!(second.try_recv().unwrap(), frame);
+        assert!(first.try_recv().is_err());
+
+        // Not ours at all.
+        dispatch(&dhcp_reply(SERVER, FIRST), &tx, &identities);
+        assert!(first.try_recv().is_err());
+    }
+
+    #[test]
+    fn broadcast_dhcp_reply_goes_by_chaddr() {
+        let (tx, _, identities, [first, second]) = setup();
+        let frame = dhcp_reply(MacAddr::broadcast(), SECOND);
+        let ethernet_packet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(dhcp_reply_chaddr(&ethernet_packet), Some(SECOND));
+
+        dispatch(&frame, &tx, &identities);
+        assert_eq!(second.try_recv().unwrap(), frame);
+        assert!(first.try_recv().is_err());
+    }
+
+    #[test]
+    fn arp_answered_only_for_claimed_addresses() {
+        let (tx, recorder, identities, _inboxes) = setup();
+        let asker = Ipv4Addr::new(192, 168, 1, 1);
+        let request = |target| {
+            build_arp(
+                ArpOperations::Request,
+                SERVER,
+                asker,
+                MacAddr::broadcast(),
+                target,
+            )
+        };
+
+        dispatch(&request(Ipv4Addr::new(192, 168, 1, 20)), &tx, &identities);
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        dispatch(&request(Ipv4Addr::new(192, 168, 1, 10)), &tx, &identities);
+        let sent = recorder.0.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let ethernet_packet = EthernetPacket::new(&sent[0]).unwrap();
+        assert_eq!(ethernet_packet.get_source(), FIRST);
+        assert_eq!(ethernet_packet.get_destination(), SERVER);
+        let arp = ArpPacket::new(ethernet_packet.payload()).unwrap();
+        assert_eq!(arp.get_operation(), ArpOperations::Reply);
+        assert_eq!(arp.get_sender_proto_addr(), Ipv4Addr::new(192, 168, 1, 10));
+        assert_eq!(arp.get_target_proto_addr(), asker);
+    }
+
+    #[test]
+    fn arp_reply_layout() {
+        let ours = MacAddr::new(0x02, 0, 0, 0, 0, 1);
+        let theirs = MacAddr::new(0x00, 0x00, 0x0c, 0, 0, 2);
+        let frame = build_arp(
+            ArpOperations::Reply,
+            ours,
+            Ipv4Addr::new(192, 168, 1, 10),
+            theirs,
+            Ipv4Addr::new(192, 168, 1, 1),
+        );
+
+        let ethernet_packet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(ethernet_packet.get_destination(), theirs);
+        let arp = ArpPacket::new(ethernet_packet.payload()).unwrap();
+        assert_eq!(arp.get_operation(), ArpOperations::Reply);
+        assert_eq!(arp.get_sender_hw_addr(), ours);
+        assert_eq!(arp.get_target_proto_addr(), Ipv4Addr::new(192, 168, 1, 1));
+    }
+}
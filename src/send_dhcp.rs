@@ -1,73 +1,65 @@
-use std::error::Error;
-use std::fmt;
-
-use std::net::Ipv4Addr;
+use dhcproto::{v4, Decodable, Decoder, Encodable, Encoder};
+use local_net::{in_netns, NetNs};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
 
 use crate::dhcp::*;
+use crate::error::{DhcpError, Error};
+use crate::lease::Lease;
 use crate::mac::get_mac;
-use pnet::datalink::{self, Channel, DataLinkReceiver, NetworkInterface};
+use crate::mac_addr::MacAddrExt;
+use crate::oui::{OuiDatabase, Vendor};
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender, NetworkInterface};
 use pnet::packet::dhcp::{Dhcp, DhcpPacket, MutableDhcpPacket};
 use pnet::packet::ethernet::EthernetPacket;
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::udp::UdpPacket;
 use pnet::packet::{FromPacket, Packet};
+use pnet::util::MacAddr;
 
-#[derive(Debug)]
-enum DhcpError {
-    Generic,
-    Specific(String),
-}
-
-// Implement the std::error::Error trait for the custom error enum
-impl Error for DhcpError {}
-
-// Implement the fmt::Display trait to customize how the error is displayed
-impl fmt::Display for DhcpError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            DhcpError::Generic => write!(f, "An unspecified dhcp error occurred."),
-            DhcpError::Specific(message) => write!(f, "A dhcp error occurred: {}", message),
-        }
-    }
-}
+/// How long we wait for the server to answer a single request.
+pub const DHCP_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Debug)]
 pub struct Network {
-    options: [Option<Ipv4Addr>; 3],
+    // Subnet mask, server identifier, DNS server and router.
+    options: [Option<Ipv4Addr>; 4],
+    server_mac: Option<MacAddr>,
 }
 
 impl Network {
     pub fn get_netmask(&self) -> Option<Ipv4Addr> {
         self.options[0]
     }
+    /// The first router (option 3).
     pub fn get_gateway(&self) -> Option<Ipv4Addr> {
+        self.options[3]
+    }
+    /// The server identifier (option 54).
+    pub fn get_server_id(&self) -> Option<Ipv4Addr> {
         self.options[1]
     }
     pub fn get_dns(&self) -> Option<Ipv4Addr> {
         self.options[2]
     }
+    /// Hardware address the offer was sent from.
+    pub fn get_server_mac(&self) -> Option<MacAddr> {
+        self.server_mac
+    }
+    pub fn get_server_vendor<'a>(&self, db: &'a OuiDatabase) -> Option<&'a Vendor> {
+        db.lookup(self.server_mac?)
+    }
 }
 
-pub fn get_network(interface_name: &str) -> Result<Network, Box<dyn Error>> {
-    let mac = get_mac(interface_name);
-    let interface = match get_interface(interface_name) {
-        Some(r) => r,
-        None => {
-            return Err(Box::new(DhcpError::Specific(
-                "Unable to find interface".to_string(),
-            )))
-        }
-    };
+pub fn get_network(interface_name: &str) -> Result<Network, Error> {
+    let mac = get_mac(interface_name)?;
+    let interface = get_interface(interface_name)
+        .ok_or_else(|| Error::InterfaceNotFound(interface_name.to_string()))?;
     dbg!("Got interface {}", &interface.name);
 
-    use dhcproto::{v4, Encodable, Encoder};
-    // construct a new Message
-    let mut msg = v4::Message::default();
-    msg.set_flags(v4::Flags::default().set_broadcast()) // set broadcast to true
-        .set_chaddr(&mac) // set chaddr
-        .opts_mut()
-        .insert(v4::DhcpOption::MessageType(v4::MessageType::Discover)); // set msg type
+    let mut msg = new_message(mac, v4::MessageType::Discover);
     msg.opts_mut()
         .insert(v4::DhcpOption::ParameterRequestList(vec![
             v4::OptionCode::SubnetMask,
@@ -75,31 +67,20 @@ pub fn get_network(interface_name: &str) -> Result<Network, Box<dyn Error>> {
             v4::OptionCode::DomainNameServer,
             v4::OptionCode::DomainName,
         ]));
-    msg.opts_mut()
-        // why would this ever be a vec
-        .insert(v4::DhcpOption::ClientIdentifier(mac.to_vec()));
 
     dbg!("Built dhcp_wrapper");
 
     let mut buf = Vec::<u8>::new();
     //
     let mut e = Encoder::new(&mut buf);
-    msg.encode(&mut e).unwrap();
+    msg.encode(&mut e)
+        .map_err(|e| DhcpError::Malformed(e.to_string()))?;
     let eframe = &mut build_dhcp_to_layer2(buf, &interface);
     dbg!("Built ethernet frame");
 
-    let res = match get_dhcp_offer(
-        msg.xid(),
-        send_packet(&interface, eframe.to_immutable()),
-        mac,
-    ) {
-        Some(r) => r,
-        None => {
-            return Err(Box::new(DhcpError::Specific(
-                "Unable create get dhcp response".to_string(),
-            )))
-        }
-    };
+    let mut transport = InterfaceTransport::open(&interface)?;
+    transport.send_frame(eframe.packet())?;
+    let (res, server_mac) = get_dhcp_offer(msg.xid(), &mut transport)?;
 
     dbg!("Got dhcp offer");
 
@@ -107,7 +88,189 @@ pub fn get_network(interface_name: &str) -> Result<Network, Box<dyn Error>> {
 
     MutableDhcpPacket::populate(&mut dhcp_packet, &res);
 
-    Ok(format_dhcp_offer(dhcp_packet.to_immutable()))
+    let mut network = format_dhcp_offer(dhcp_packet.to_immutable())?;
+    network.server_mac = Some(server_mac);
+    Ok(network)
+}
+
+fn new_message(mac: MacAddr, message_type: v4::MessageType) -> v4::Message {
+    let chaddr = mac.to_bytes();
+    let mut msg = v4::Message::default();
+    msg.set_flags(v4::Flags::default().set_broadcast())
+        .set_chaddr(&chaddr)
+        .opts_mut()
+        .insert(v4::DhcpOption::MessageType(message_type));
+    // Client identifier type 1 is an ethernet address (RFC 2132 9.14).
+    let mut client_id = vec![1];
+    client_id.extend_from_slice(&chaddr);
+    msg.opts_mut()
+        .insert(v4::DhcpOption::ClientIdentifier(client_id));
+    msg
+}
+
+/// Send `msg` and wait for a reply of one of the `expected` types. A DHCPNAK is always
+/// accepted and turned into an error.
+fn exchange(
+    transport: &mut dyn FrameTransport,
+    msg: &v4::Message,
+    expected: &[v4::MessageType],
+) -> Result<(v4::Message, MacAddr), Error> {
+    let mut buf = Vec::<u8>::new();
+    msg.encode(&mut Encoder::new(&mut buf))
+        .map_err(|e| DhcpError::Malformed(e.to_string()))?;
+    let eframe = build_dhcp_frame(buf, transport.mac());
+    transport.send_frame(eframe.packet())?;
+
+    let deadline = Instant::now() + DHCP_TIMEOUT;
+    loop {
+        let (payload, server_mac) = get_dhcp_reply(msg.xid(), transport, deadline)?;
+        let reply = v4::Message::decode(&mut Decoder::new(&payload))
+            .map_err(|e| DhcpError::Malformed(e.to_string()))?;
+
+        match reply.opts().msg_type() {
+            Some(v4::MessageType::Nak) => return Err(nak_error(&reply)),
+            Some(message_type) if expected.contains(&message_type) => {
+                return Ok((reply, server_mac))
+            }
+            // Retransmitted or late replies to an earlier step.
+            _ => continue,
+        }
+    }
+}
+
+fn nak_error(nak: &v4::Message) -> Error {
+    Error::Dhcp(DhcpError::Nak {
+        server: server_identifier(nak),
+        message: match nak.opts().get(v4::OptionCode::Message) {
+            Some(v4::DhcpOption::Message(message)) => Some(message.clone()),
+            _ => None,
+        },
+    })
+}
+
+fn server_identifier(msg: &v4::Message) -> Option<Ipv4Addr> {
+    match msg.opts().get(v4::OptionCode::ServerIdentifier) {
+        Some(v4::DhcpOption::ServerIdentifier(id)) => Some(*id),
+        _ => None,
+    }
+}
+
+/// Run a full DISCOVER/OFFER/REQUEST/ACK exchange and return the resulting lease.
+/// Nothing is configured on the interface, see `lease::apply_lease` for that.
+pub fn obtain_lease(interface_name: &str) -> Result<Lease, Error> {
+    let interface = get_interface(interface_name)
+        .ok_or_else(|| Error::InterfaceNotFound(interface_name.to_string()))?;
+    obtain_lease_over(&mut InterfaceTransport::open(&interface)?)
+}
+
+/// Like `obtain_lease`, for an interface in another network namespace.
+pub fn obtain_lease_in(ns: &NetNs, interface_name: &str) -> Result<Lease, Error> {
+    in_netns(ns, || obtain_lease(interface_name))?
+}
+
+/// Like `obtain_lease`, but for whatever hardware address `transport` sends from.
+pub fn obtain_lease_over(transport: &mut dyn FrameTransport) -> Result<Lease, Error> {
+    let mac = transport.mac();
+
+    let mut discover = new_message(mac, v4::MessageType::Discover);
+    discover
+        .opts_mut()
+        .insert(v4::DhcpOption::ParameterRequestList(vec![
+            v4::OptionCode::SubnetMask,
+            v4::OptionCode::Router,
+            v4::OptionCode::DomainNameServer,
+            v4::OptionCode::BroadcastAddr,
+        ]));
+    let (offer, _) = exchange(transport, &discover, &[v4::MessageType::Offer])?;
+    let server_id = server_identifier(&offer)
+        .ok_or_else(|| DhcpError::Malformed("Offer has no server identifier".to_string()))?;
+
+    // The request reuses the discover's xid (RFC 2131 4.4.1).
+    let mut request = new_message(mac, v4::MessageType::Request);
+    request.set_xid(discover.xid());
+    request
+        .opts_mut()
+        .insert(v4::DhcpOption::RequestedIpAddress(offer.yiaddr()));
+    request
+        .opts_mut()
+        .insert(v4::DhcpOption::ServerIdentifier(server_id));
+    let (ack, server_mac) = exchange(transport, &request, &[v4::MessageType::Ack])?;
+
+    Lease::from_ack(&ack, mac, server_mac)
+}
+
+/// Give the lease back to the server. Has to be called while the leased address is still
+/// configured, since the release is sent from it.
+pub fn release_lease(lease: &Lease) -> Result<(), Error> {
+    let mut release = new_message(lease.client_mac, v4::MessageType::Release);
+    release.set_flags(v4::Flags::default());
+    release.set_ciaddr(lease.address);
+    release
+        .opts_mut()
+        .insert(v4::DhcpOption::ServerIdentifier(lease.server_id));
+
+    let mut buf = Vec::<u8>::new();
+    release
+        .encode(&mut Encoder::new(&mut buf))
+        .map_err(|e| DhcpError::Malformed(e.to_string()))?;
+
+    // Releases are unicast and unanswered (RFC 2131 4.4.4).
+    let socket = UdpSocket::bind((lease.address, 68))?;
+    socket.send_to(&buf, (lease.server_id, 67))?;
+    Ok(())
+}
+
+/// Extend the lease with the server that granted it (RENEWING, RFC 2131 4.4.5). Like
+/// `release_lease` this is unicast from the leased address, which has to be configured.
+/// A DHCPNAK means the address has to be given up.
+pub fn renew_lease(lease: &Lease) -> Result<Lease, Error> {
+    let mut request = new_message(lease.client_mac, v4::MessageType::Request);
+    // The server answers to ciaddr.
+    request.set_flags(v4::Flags::default());
+    request.set_ciaddr(lease.address);
+
+    let mut buf = Vec::<u8>::new();
+    request
+        .encode(&mut Encoder::new(&mut buf))
+        .map_err(|e| DhcpError::Malformed(e.to_string()))?;
+
+    let socket = UdpSocket::bind((lease.address, 68))?;
+    socket.send_to(&buf, (lease.server_id, 67))?;
+
+    let deadline = Instant::now() + DHCP_TIMEOUT;
+    let mut received = [0u8; 1500];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(Error::Dhcp(DhcpError::Timeout));
+        }
+        socket.set_read_timeout(Some(left))?;
+        let len = match socket.recv(&mut received) {
+            Ok(len) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(Error::Dhcp(DhcpError::Timeout))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // Anything else arriving on port 68 of this address is not ours.
+        let reply = match v4::Message::decode(&mut Decoder::new(&received[..len])) {
+            Ok(reply) if reply.xid() == request.xid() => reply,
+            _ => continue,
+        };
+
+        match reply.opts().msg_type() {
+            Some(v4::MessageType::Ack) => {
+                return Lease::from_ack(&reply, lease.client_mac, lease.server_mac)
+            }
+            Some(v4::MessageType::Nak) => return Err(nak_error(&reply)),
+            _ => continue,
+        }
+    }
+}
+
+/// Like `release_lease`, for a lease held in another network namespace.
+pub fn release_lease_in(ns: &NetNs, lease: &Lease) -> Result<(), Error> {
+    in_netns(ns, || release_lease(lease))?
 }
 
 fn get_interface(interface_name: &str) -> Option<NetworkInterface> {
@@ -116,37 +279,114 @@ fn get_interface(interface_name: &str) -> Option<NetworkInterface> {
         .find(|iface| iface.name == interface_name)
 }
 
-fn send_packet(interface: &NetworkInterface, packet: EthernetPacket) -> Box<dyn DataLinkReceiver> {
-    // Send the packet
-    let (mut tx, rx) = match datalink::channel(interface, Default::default()) {
-        Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
-        Ok(_) => panic!("Unknown channel type"),
-        Err(e) => panic!("Error creating datalink channel: {}", e),
-    };
+/// A link DHCP frames can be exchanged over.
+pub trait FrameTransport {
+    /// The hardware address frames are sent from.
+    fn mac(&self) -> MacAddr;
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error>;
+    /// Wait a short while for the next frame. `Ok(None)` if none arrived in time.
+    fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// A datalink channel on an interface, sending from the interface's own address.
+struct InterfaceTransport {
+    name: String,
+    mac: MacAddr,
+    tx: Box<dyn DataLinkSender>,
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+impl InterfaceTransport {
+    fn open(interface: &NetworkInterface) -> Result<InterfaceTransport, Error> {
+        let mac = interface
+            .mac
+            .ok_or_else(|| Error::InterfaceNotFound(interface.name.clone()))?;
+
+        // A read timeout lets get_dhcp_reply give up instead of blocking forever.
+        let config = datalink::Config {
+            read_timeout: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
 
-    tx.send_to(packet.packet(), None);
+        let (tx, rx) = match datalink::channel(interface, config) {
+            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
+            Ok(_) => {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "Unknown channel type",
+                )))
+            }
+            Err(e) => return Err(Error::from_io(&interface.name, e)),
+        };
 
-    rx
+        Ok(InterfaceTransport {
+            name: interface.name.clone(),
+            mac,
+            tx,
+            rx,
+        })
+    }
+}
+
+impl FrameTransport for InterfaceTransport {
+    fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), Error> {
+        match self.tx.send_to(frame, None) {
+            Some(Err(e)) => Err(Error::from_io(&self.name, e)),
+            Some(Ok(())) => Ok(()),
+            None => Err(Error::Io(std::io::Error::other(
+                "Datalink channel did not send the packet",
+            ))),
+        }
+    }
+
+    fn recv_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        match self.rx.next() {
+            Ok(frame) => Ok(Some(frame.to_vec())),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
 }
 
 // This is most likely the hottest peice of code. To optimize this, we should merely go to
 // predetermined offsets in the packet.
-fn get_dhcp_offer(xid: u32, mut rx: Box<dyn DataLinkReceiver>, mac: [u8; 14]) -> Option<Dhcp> {
-    while let Ok(base_packet) = rx.next() {
+fn get_dhcp_offer(xid: u32, transport: &mut dyn FrameTransport) -> Result<(Dhcp, MacAddr), Error> {
+    let (payload, server_mac) = get_dhcp_reply(xid, transport, Instant::now() + DHCP_TIMEOUT)?;
+
+    let dhcp_packet = DhcpPacket::new(&payload)
+        .ok_or_else(|| DhcpError::Malformed("Reply is too short".to_string()))?;
+    Ok((dhcp_packet.from_packet(), server_mac))
+}
+
+/// Wait for a DHCP reply with our xid. Returns the DHCP payload and the hardware address it
+/// was sent from.
+fn get_dhcp_reply(
+    xid: u32,
+    transport: &mut dyn FrameTransport,
+    deadline: Instant,
+) -> Result<(Vec<u8>, MacAddr), Error> {
+    loop {
+        if Instant::now() >= deadline {
+            return Err(Error::Dhcp(DhcpError::Timeout));
+        }
+
+        let base_packet = match transport.recv_frame()? {
+            Some(packet) => packet,
+            None => continue,
+        };
+
         // Process the received packet
-        let ethernet_packet = match EthernetPacket::new(base_packet) {
-            Some(packet) => {
-                dbg!(&packet);
-                packet
-            }
+        let ethernet_packet = match EthernetPacket::new(&base_packet) {
+            Some(packet) => packet,
             None => continue, // Skip packets that are not Ethernet
         };
 
         let ipv4_packet = match Ipv4Packet::new(ethernet_packet.payload()) {
-            Some(packet) => {
-                dbg!(&packet);
-                packet
-            }
+            Some(packet) => packet,
             None => continue, // Skip packets that are not IPv4
         };
 
@@ -155,14 +395,10 @@ fn get_dhcp_offer(xid: u32, mut rx: Box<dyn DataLinkReceiver>, mac: [u8; 14]) ->
         }
 
         let udp_packet = match UdpPacket::new(ipv4_packet.payload()) {
-            Some(packet) => {
-                dbg!(&packet);
-                packet
-            }
+            Some(packet) => packet,
             None => continue, // Skip packets that are not UDP
         };
 
-        dbg!(udp_packet.get_destination());
         if udp_packet.get_destination() != 68 {
             continue;
         }
@@ -172,52 +408,109 @@ fn get_dhcp_offer(xid: u32, mut rx: Box<dyn DataLinkReceiver>, mac: [u8; 14]) ->
             None => continue, // Skip packets that are not DHCP
         };
 
-        dbg!("Got a DHCP packet");
-        println!("{} {:#?}", ethernet_packet.get_destination(), mac);
-
         if dhcp_packet.get_xid() == xid {
-            return Some(dhcp_packet.from_packet());
+            return Ok((udp_packet.payload().to_vec(), ethernet_packet.get_source()));
         };
-        dbg!("Incoming DHCP packet has wrong xid", xid);
     }
-    None
 }
 
 fn ipv4_from_u8_array(array: &[u8; 4]) -> Ipv4Addr {
     Ipv4Addr::new(array[0], array[1], array[2], array[3])
 }
 
-fn format_dhcp_offer(dhcp_offer_packet: DhcpPacket) -> Network {
+fn format_dhcp_offer(dhcp_offer_packet: DhcpPacket) -> Result<Network, Error> {
     let dhcp_offer = dhcp_offer_packet.from_packet();
     let mut index = 0;
     let options_data = dhcp_offer.options;
     let mut net: Network = Network {
-        options: [None, None, None],
+        options: [None; 4],
+        server_mac: None,
     };
-    while index < options_data.len() - 1 {
+    let mut is_nak = false;
+    let mut nak_message = None;
+    while index + 1 < options_data.len() {
         let code = options_data[index];
         if code == 0x63 {
             index += 4;
             continue;
         }
+        // Pad and end options have no length byte.
+        if code == 0x00 {
+            index += 1;
+            continue;
+        }
+        if code == 0xff {
+            break;
+        }
+
+        let length = options_data[index + 1] as usize;
+        if index + 2 + length > options_data.len() {
+            return Err(Error::Dhcp(DhcpError::Malformed(format!(
+                "option {} overruns the packet",
+                code
+            ))));
+        }
+        if [0x01, 0x03, 0x36, 0x06].contains(&code) && length < 4 {
+            return Err(Error::Dhcp(DhcpError::Malformed(format!(
+                "option {} is too short for an address",
+                code
+            ))));
+        }
 
         use arrayref::array_ref;
-        if code == 0x01 {
+        if code == 0x35 {
+            // Message type 6 is DHCPNAK.
+            is_nak = length == 1 && options_data[index + 2] == 6;
+        } else if code == 0x38 {
+            let start = index + 2;
+            nak_message =
+                Some(String::from_utf8_lossy(&options_data[start..start + length]).to_string());
+        } else if code == 0x01 {
             let start = index + 2;
             let netmask = array_ref!(options_data, start, 4);
             net.options[0] = Some(ipv4_from_u8_array(netmask));
-        } else if code == 0x36 {
+        } else if code == 0x03 {
             let start = index + 2;
             let router = array_ref!(options_data, start, 4);
-            net.options[1] = Some(ipv4_from_u8_array(router));
+            net.options[3] = Some(ipv4_from_u8_array(router));
+        } else if code == 0x36 {
+            let start = index + 2;
+            let server_id = array_ref!(options_data, start, 4);
+            net.options[1] = Some(ipv4_from_u8_array(server_id));
         } else if code == 0x06 {
             let start = index + 2;
             let dns = array_ref!(options_data, start, 4);
             net.options[2] = Some(ipv4_from_u8_array(dns));
         }
 
-        let length = options_data[index + 1] as usize;
         index += length + 2;
     }
-    net
+
+    if is_nak {
+        return Err(Error::Dhcp(DhcpError::Nak {
+            server: net.options[1],
+            message: nak_message,
+        }));
+    }
+    Ok(net)
+}
+
+#[cfg(all(test, feature = "testbed"))]
+mod test_send_dhcp {
+    use super::*;
+    use local_net::{TestNet, CLIENT_IFACE};
+
+    #[tokio::test]
+    async fn obtain_lease_in_namespace() {
+        let mut net = TestNet::new().await.unwrap();
+        let server = Ipv4Addr::new(10, 9, 0, 1);
+        let range = (Ipv4Addr::new(10, 9, 0, 100), Ipv4Addr::new(10, 9, 0, 150));
+        net.start_dhcp_server(server, 24, range).await.unwrap();
+
+        let lease = obtain_lease_in(&net.client, CLIENT_IFACE).unwrap();
+        assert!(lease.address >= range.0 && lease.address <= range.1);
+        assert_eq!(lease.server_id, server);
+        assert_eq!(lease.gateway(), Some(server));
+        assert_eq!(lease.prefix_len(), 24);
+    }
 }
@@ -0,0 +1,251 @@
+// Per-network stable random MAC addresses, like the "randomized MAC" of Android and iOS.
+// The address is derived from a local secret and a fingerprint of the network, so it is the
+// same every time we join a network but differs between networks. Derived addresses are
+// also written down so a network keeps its address (and therefore its lease) even if the
+// secret is lost or the derivation changes.
+use hmac::{Hmac, Mac};
+use pnet::util::MacAddr;
+use rand::RngCore;
+use rtnetlink::Handle;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::mac::set_mac;
+use crate::mac_addr::MacAddrExt;
+use crate::send_dhcp::Network;
+
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/netmanager-rs";
+const SECRET_FILE: &str = "stable_mac_secret";
+const MAPPING_FILE: &str = "stable_macs";
+const SECRET_LEN: usize = 32;
+
+/// What identifies a network. Any field we couldn't learn is left out of the fingerprint.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NetworkFingerprint {
+    /// Hardware address the DHCP offer came from.
+    pub dhcp_server_mac: Option<MacAddr>,
+    pub dhcp_server_id: Option<Ipv4Addr>,
+    /// SSIDs are up to 32 arbitrary bytes, not necessarily text.
+    pub ssid: Option<Vec<u8>>,
+}
+
+impl NetworkFingerprint {
+    pub fn from_network(network: &Network, ssid: Option<Vec<u8>>) -> NetworkFingerprint {
+        NetworkFingerprint {
+            dhcp_server_mac: network.get_server_mac(),
+            dhcp_server_id: network.get_server_id(),
+            ssid,
+        }
+    }
+
+    /// Canonical text form, used both as HMAC input and as the key in the mapping file.
+    pub fn key(&self) -> String {
+        let field = |value: Option<String>| value.unwrap_or_default();
+        format!(
+            "server_mac={};server={};ssid={}",
+            field(self.dhcp_server_mac.map(|mac| mac.to_string())),
+            field(self.dhcp_server_id.map(|id| id.to_string())),
+            // Hex, so no SSID can contain a separator or end the line in the mapping file.
+            field(
+                self.ssid
+                    .as_ref()
+                    .map(|ssid| { ssid.iter().map(|byte| format!("{:02x}", byte)).collect() })
+            ),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &NetworkFingerprint::default()
+    }
+}
+
+pub struct StableMacStore {
+    dir: PathBuf,
+    secret: [u8; SECRET_LEN],
+    mappings: BTreeMap<String, MacAddr>,
+}
+
+impl StableMacStore {
+    /// Open the store in `dir`, creating the directory and the secret on first use.
+    pub fn open(dir: &Path) -> Result<StableMacStore, Error> {
+        fs::create_dir_all(dir)?;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+
+        Ok(StableMacStore {
+            dir: dir.to_path_buf(),
+            secret: load_or_create_secret(&dir.join(SECRET_FILE))?,
+            mappings: load_mappings(&dir.join(MAPPING_FILE))?,
+        })
+    }
+
+    /// The address to use on the network described by `fingerprint`.
+    pub fn mac_for(&mut self, fingerprint: &NetworkFingerprint) -> Result<MacAddr, Error> {
+        if fingerprint.is_empty() {
+            return Err(Error::InvalidArgument(
+                "Cannot derive a stable MAC from an empty fingerprint".to_string(),
+            ));
+        }
+
+        let key = fingerprint.key();
+        if let Some(mac) = self.mappings.get(&key) {
+            return Ok(*mac);
+        }
+
+        let mac = derive_mac(&self.secret, &key);
+        self.mappings.insert(key, mac);
+        self.save_mappings()?;
+        Ok(mac)
+    }
+
+    /// Give `interface` the stable address for `fingerprint`.
+    pub async fn apply(
+        &mut self,
+        handle: &Handle,
+        interface: &str,
+        fingerprint: &NetworkFingerprint,
+    ) -> Result<MacAddr, Error> {
+        let mac = self.mac_for(fingerprint)?;
+        set_mac(handle, interface, mac).await?;
+        Ok(mac)
+    }
+
+    fn save_mappings(&self) -> Result<(), Error> {
+        // Write to a temporary file and rename so a crash can't leave a truncated mapping.
+        let path = self.dir.join(MAPPING_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", MAPPING_FILE));
+
+        let mut file = fs::File::create(&tmp_path)?;
+        for (key, mac) in self.mappings.iter() {
+            writeln!(file, "{}\t{}", mac, key)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// HMAC-SHA256(secret, key), truncated to a unicast, locally administered address.
+fn derive_mac(secret: &[u8], key: &str) -> MacAddr {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+    hmac.update(key.as_bytes());
+    let digest = hmac.finalize().into_bytes();
+
+    MacAddr::from_slice(&digest)
+        .expect("SHA256 digests are 32 bytes")
+        .to_unicast()
+        .to_local()
+}
+
+fn load_or_create_secret(path: &Path) -> Result<[u8; SECRET_LEN], Error> {
+    let mut secret = [0u8; SECRET_LEN];
+
+    match fs::read(path) {
+        Ok(contents) if contents.len() == SECRET_LEN => {
+            secret.copy_from_slice(&contents);
+            return Ok(secret);
+        }
+        Ok(_) => {
+            return Err(Error::InvalidDatabase(format!(
+                "{} is not a {} byte secret",
+                path.display(),
+                SECRET_LEN
+            )))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(Error::Io(e)),
+    }
+
+    rand::thread_rng().fill_bytes(&mut secret);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&secret)?;
+    file.sync_all()?;
+    Ok(secret)
+}
+
+fn load_mappings(path: &Path) -> Result<BTreeMap<String, MacAddr>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (mac, key) = line.split_once('\t').ok_or_else(|| {
+                Error::InvalidDatabase(format!("Invalid stable MAC mapping: {}", line))
+            })?;
+            let mac = MacAddr::parse(mac).map_err(|_| {
+                Error::InvalidDatabase(format!("Invalid stable MAC mapping: {}", line))
+            })?;
+            Ok((key.to_string(), mac))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_stable_mac {
+    use super::*;
+    use crate::mac_addr::MacClass;
+
+    #[test]
+    fn stable_per_network() {
+        let dir = std::env::temp_dir().join(format!("stable_mac_test_{}", std::process::id()));
+        let home = NetworkFingerprint {
+            dhcp_server_mac: Some(MacAddr::new(0x00, 0x00, 0x0c, 0x01, 0x02, 0x03)),
+            dhcp_server_id: Some(Ipv4Addr::new(192, 168, 1, 1)),
+            ssid: None,
+        };
+        let cafe = NetworkFingerprint {
+            ssid: Some(b"cafe".to_vec()),
+            ..Default::default()
+        };
+
+        let mut store = StableMacStore::open(&dir).unwrap();
+        let home_mac = store.mac_for(&home).unwrap();
+        assert_ne!(home_mac, store.mac_for(&cafe).unwrap());
+        assert_eq!(home_mac.class(), MacClass::LocalUnicast);
+
+        // Reopening must give the same answer.
+        let mut store = StableMacStore::open(&dir).unwrap();
+        assert_eq!(home_mac, store.mac_for(&home).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ssid_cannot_break_the_mapping_file() {
+        let dir = std::env::temp_dir().join(format!("stable_mac_ssid_{}", std::process::id()));
+        let odd = NetworkFingerprint {
+            ssid: Some(b"evil\nserver=1.2.3.4;\t\xff".to_vec()),
+            ..Default::default()
+        };
+        assert_eq!(
+            odd.key(),
+            "server_mac=;server=;ssid=6576696c0a7365727665723d312e322e332e343b09ff"
+        );
+
+        let mut store = StableMacStore::open(&dir).unwrap();
+        let mac = store.mac_for(&odd).unwrap();
+        let mut store = StableMacStore::open(&dir).unwrap();
+        assert_eq!(mac, store.mac_for(&odd).unwrap());
+
+        assert!(matches!(
+            store.mac_for(&NetworkFingerprint::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
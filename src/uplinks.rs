@@ -0,0 +1,164 @@
+// Several networks at once, with connections spread over them. Every uplink with a healthy
+// lease becomes a nexthop of one multipath default route; the kernel hashes each flow onto
+// one of them, weighted.
+use local_net::{del_route, get_routes, replace_route, NextHop, Route, Scope, Table};
+use rtnetlink::Handle;
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::error::Error;
+use crate::lease::Lease;
+
+/// Metric of the multipath default route. Below what DHCP clients usually install, so ours
+/// wins while it exists.
+pub const MULTIPATH_METRIC: u32 = 50;
+
+#[derive(Clone, Debug)]
+pub struct Uplink {
+    pub name: String,
+    pub iface_idx: u32,
+    /// Share of new connections relative to the other uplinks, 1..=256.
+    pub weight: u16,
+    pub lease: Option<Lease>,
+}
+
+impl Uplink {
+    /// Has a lease that hasn't run out, with a gateway to send through.
+    pub fn healthy(&self) -> bool {
+        self.lease
+            .as_ref()
+            .is_some_and(|lease| !lease.expired() && lease.gateway().is_some())
+    }
+
+    fn nexthop(&self) -> Option<NextHop> {
+        let gateway = self.lease.as_ref()?.gateway()?;
+        Some(NextHop {
+            iface_idx: self.iface_idx,
+            gateway: Some(IpAddr::V4(gateway)),
+            weight: self.weight,
+        })
+    }
+}
+
+pub struct Uplinks {
+    handle: Handle,
+    table: Table,
+    uplinks: Vec<Uplink>,
+    // What `sync` last installed.
+    installed: Option<Route>,
+}
+
+impl Uplinks {
+    /// Manage the default route of `table` (usually `Table::Main`).
+    pub fn new(handle: Handle, table: Table) -> Uplinks {
+        Uplinks {
+            handle,
+            table,
+            uplinks: Vec::new(),
+            installed: None,
+        }
+    }
+
+    pub fn add(&mut self, name: &str, iface_idx: u32, weight: u16) {
+        self.uplinks.retain(|uplink| uplink.iface_idx != iface_idx);
+        self.uplinks.push(Uplink {
+            name: name.to_string(),
+            iface_idx,
+            weight,
+            lease: None,
+        });
+    }
+
+    pub fn remove(&mut self, iface_idx: u32) -> Option<Uplink> {
+        let position = self
+            .uplinks
+            .iter()
+            .position(|uplink| uplink.iface_idx == iface_idx)?;
+        Some(self.uplinks.remove(position))
+    }
+
+    /// Record a new lease (or the loss of one) for an uplink. Takes effect on the next `sync`.
+    pub fn set_lease(&mut self, iface_idx: u32, lease: Option<Lease>) -> Result<(), Error> {
+        match self
+            .uplinks
+            .iter_mut()
+            .find(|uplink| uplink.iface_idx == iface_idx)
+        {
+            Some(uplink) => {
+                uplink.lease = lease;
+                Ok(())
+            }
+            None => Err(Error::UnknownUplink(iface_idx)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Uplink> {
+        self.uplinks.iter()
+    }
+
+    fn default_route(&self) -> Option<Route> {
+        let healthy: Vec<&Uplink> = self.uplinks.iter().filter(|u| u.healthy()).collect();
+        let mut route = match healthy.as_slice() {
+            [] => return None,
+            // The kernel turns a single nexthop into a plain route; install it as one so it
+            // reads back the same.
+            [uplink] => Route::new(
+                uplink.iface_idx,
+                Ipv4Addr::UNSPECIFIED.into(),
+                0,
+                uplink.nexthop()?.gateway,
+            ),
+            uplinks => Route {
+                iface_idx: None,
+                gateway: None,
+                scope: Scope::Universe,
+                nexthops: uplinks.iter().filter_map(|u| u.nexthop()).collect(),
+                ..Route::new(0, Ipv4Addr::UNSPECIFIED.into(), 0, None)
+            },
+        };
+        route.table = self.table;
+        route.metric = Some(MULTIPATH_METRIC);
+        Some(route)
+    }
+
+    // Whether the kernel still has `route`. It drops routes through a link that goes down,
+    // without us noticing.
+    async fn in_kernel(&self, route: &Route) -> Result<bool, Error> {
+        let routes = get_routes(&self.handle).await?;
+        Ok(routes.iter().any(|found| {
+            found.destination == route.destination
+                && found.prefix_len == route.prefix_len
+                && found.table == route.table
+                && found.metric == route.metric
+                && found.iface_idx == route.iface_idx
+                && found.gateway == route.gateway
+                && found.nexthops == route.nexthops
+        }))
+    }
+
+    /// Bring the default route in line with the uplinks that are healthy right now. Call it
+    /// after `set_lease`, on link changes and periodically, since leases also expire on their
+    /// own. Reinstalls the route if it disappeared from the kernel.
+    pub async fn sync(&mut self) -> Result<(), Error> {
+        let wanted = self.default_route();
+        let unchanged = match &wanted {
+            Some(route) => wanted == self.installed && self.in_kernel(route).await?,
+            None => self.installed.is_none(),
+        };
+        if unchanged {
+            return Ok(());
+        }
+
+        match &wanted {
+            // Destination, table and metric are the same for every version of our route, so
+            // the kernel swaps the old one out in one step.
+            Some(route) => replace_route(&self.handle, route.clone()).await?,
+            None => {
+                if let Some(installed) = &self.installed {
+                    del_route(&self.handle, installed).await?
+                }
+            }
+        }
+        self.installed = wanted;
+        Ok(())
+    }
+}